@@ -0,0 +1,31 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+use tiny_keccak::{Hasher as _, Sha3};
+
+use merkletree::Hash;
+use merkletree::hash::{Hasher, ShaHasher};
+
+fn two_updates(left: &[u8], right: &[u8]) -> Hash {
+    let mut sha = Sha3::v256();
+    sha.update(left);
+    sha.update(right);
+    let mut hash = [0u8; 32];
+    sha.finalize(&mut hash);
+    hash
+}
+
+/// `ShaHasher::concat_hash` (children copied into a `[u8; 64]`, one `update`)
+/// against absorbing the two children with separate `update` calls
+fn mt_concat_benchmark(c: &mut Criterion) {
+    let hasher = ShaHasher::default();
+    let left = hasher.generate_hash("left".as_bytes());
+    let right = hasher.generate_hash("right".as_bytes());
+    assert_eq!(hasher.concat_hash(&left, &right), two_updates(&left, &right));
+
+    let mut group = c.benchmark_group("SHA3-256 concat_hash");
+    group.bench_function("stack buffer", |b| b.iter(|| hasher.concat_hash(black_box(&left), black_box(&right))));
+    group.bench_function("two updates", |b| b.iter(|| two_updates(black_box(&left), black_box(&right))));
+    group.finish();
+}
+
+criterion_group!(benches, mt_concat_benchmark);
+criterion_main!(benches);
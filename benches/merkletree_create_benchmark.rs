@@ -1,8 +1,5 @@
 use criterion::{black_box, Criterion, criterion_group, criterion_main};
-use rand::Rng;
-use rand::seq::SliceRandom;
-
-use merkletree::{Hash, MerkleTree};
+use merkletree::MerkleTree;
 use merkletree::hash::ShaHasher;
 
 fn mt_create_benchmark(c: &mut Criterion) {
@@ -15,6 +12,15 @@ fn mt_create_benchmark(c: &mut Criterion) {
                          let v = MerkleTree::new(levels, ShaHasher::default());
                          black_box(v);
                      }));
+
+    let id = format!("MT: {}. Tree clear.", levels);
+    let mut tree = MerkleTree::new(levels, ShaHasher::default());
+
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         tree.clear();
+                         black_box(&tree);
+                     }));
 }
 
 criterion_group!(benches, mt_create_benchmark);
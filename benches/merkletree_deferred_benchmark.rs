@@ -0,0 +1,44 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::ShaHasher;
+
+fn mt_deferred_benchmark(c: &mut Criterion) {
+    let levels = 16;
+    let leaves: Vec<Hash> = {
+        let tree = MerkleTree::new(1, ShaHasher::default());
+        (0..1u32 << (levels - 1)).map(|i| tree.generate_hash(&i.to_be_bytes())).collect()
+    };
+
+    let id = format!("MT: {}. Eager add.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let mut tree = MerkleTree::new(levels, ShaHasher::default());
+                         for leaf in &leaves {
+                             tree.add(*leaf);
+                         }
+                         black_box(tree);
+                     }));
+
+    let id = format!("MT: {}. Deferred add.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let mut tree = MerkleTree::new(levels, ShaHasher::default());
+                         tree.begin_defer();
+                         for leaf in &leaves {
+                             tree.add(*leaf);
+                         }
+                         tree.commit();
+                         black_box(tree);
+                     }));
+
+    let id = format!("MT: {}. from_leaves.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let tree = MerkleTree::from_leaves(levels, ShaHasher::default(), &leaves).unwrap();
+                         black_box(tree);
+                     }));
+}
+
+criterion_group!(benches, mt_deferred_benchmark);
+criterion_main!(benches);
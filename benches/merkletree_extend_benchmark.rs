@@ -0,0 +1,36 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::ShaHasher;
+
+/// Filling an empty level 20 tree with `extend` against building it with `from_leaves`.
+/// Both hash every internal node once, so they should converge
+fn mt_extend_benchmark(c: &mut Criterion) {
+    let levels = 20;
+    let leaves: Vec<Hash> = {
+        let tree = MerkleTree::new(1, ShaHasher::default());
+        (0..MerkleTree::capacity_for(levels)).map(|i| tree.generate_hash(&i.to_be_bytes())).collect()
+    };
+
+    let mut extended = MerkleTree::new(levels, ShaHasher::default());
+    extended.extend(leaves.iter().copied());
+    assert_eq!(extended.root_hash(), MerkleTree::from_leaves(levels, ShaHasher::default(), &leaves).unwrap().root_hash());
+
+    let id = format!("MT: {}. Extend empty tree.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let mut tree = MerkleTree::new(levels, ShaHasher::default());
+                         tree.extend(leaves.iter().copied());
+                         black_box(tree);
+                     }));
+
+    let id = format!("MT: {}. from_leaves.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let tree = MerkleTree::from_leaves(levels, ShaHasher::default(), &leaves).unwrap();
+                         black_box(tree);
+                     }));
+}
+
+criterion_group!(benches, mt_extend_benchmark);
+criterion_main!(benches);
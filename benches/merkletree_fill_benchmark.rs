@@ -0,0 +1,41 @@
+use criterion::{BatchSize, black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::ShaHasher;
+
+/// Latency of a single `add` into a tree already filled to 10%, 50% and 90%.
+///
+/// The prefilled tree is built in the untimed setup and only the one `add` is measured,
+/// so a flat result across fill levels means the floating root bookkeeping in `add`
+/// doesn't grow with size. Branch hashing itself is bounded by `levels` either way.
+fn mt_fill_benchmark(c: &mut Criterion) {
+    let levels = 16;
+    let capacity = MerkleTree::capacity_for(levels);
+    let leaves: Vec<Hash> = {
+        let tree = MerkleTree::new(1, ShaHasher::default());
+        (0..capacity).map(|i| tree.generate_hash(&i.to_be_bytes())).collect()
+    };
+
+    for &percent in &[10u32, 50, 90] {
+        let size = (capacity / 100 * percent) as usize;
+        let id = format!("MT: {}. Capacity: {}. Size: {}. Add at {}% fill.", levels, capacity, size, percent);
+        let prefilled = || {
+            let mut tree = MerkleTree::new(levels, ShaHasher::default());
+            tree.begin_defer();
+            for leaf in &leaves[..size] {
+                tree.add(*leaf);
+            }
+            tree.commit();
+            tree
+        };
+
+        c.bench_function(id.as_str(),
+                         |b| b.iter_batched(prefilled, |mut tree| {
+                             tree.add(black_box(leaves[size]));
+                             tree
+                         }, BatchSize::LargeInput));
+    }
+}
+
+criterion_group!(benches, mt_fill_benchmark);
+criterion_main!(benches);
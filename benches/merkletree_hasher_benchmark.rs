@@ -0,0 +1,28 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::{Blake3Hasher, Hasher, ShaHasher};
+
+fn build(levels: u32, leaves: &[Hash], hasher: impl Hasher + 'static) -> MerkleTree {
+    let mut tree = MerkleTree::new(levels, hasher);
+    tree.begin_defer();
+    for leaf in leaves {
+        tree.add(*leaf);
+    }
+    tree.commit();
+    tree
+}
+
+fn mt_hasher_benchmark(c: &mut Criterion) {
+    let levels = 20;
+    let leaves: Vec<Hash> = (0..MerkleTree::capacity_for(levels)).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+
+    let mut group = c.benchmark_group(format!("MT: {}. Build", levels));
+    group.sample_size(10);
+    group.bench_function("sha3-256", |b| b.iter(|| black_box(build(levels, &leaves, ShaHasher::default()))));
+    group.bench_function("blake3", |b| b.iter(|| black_box(build(levels, &leaves, Blake3Hasher::default()))));
+    group.finish();
+}
+
+criterion_group!(benches, mt_hasher_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::MerkleTree;
+use merkletree::hash::{MemoHasher, ShaHasher};
+
+fn fill(tree: &mut MerkleTree) {
+    let leaves = [tree.generate_hash("hello".as_bytes()), tree.generate_hash("12345".as_bytes())];
+    for i in 0..tree.capacity() {
+        tree.add(leaves[(i % 2) as usize]);
+    }
+}
+
+fn mt_memo_benchmark(c: &mut Criterion) {
+    let levels = 14;
+
+    let id = format!("MT: {}. Repeated leaves. Sha3.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let mut tree = MerkleTree::new(levels, ShaHasher::default());
+                         fill(&mut tree);
+                         black_box(tree);
+                     }));
+
+    let id = format!("MT: {}. Repeated leaves. Memo Sha3.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         let mut tree = MerkleTree::new(levels, MemoHasher::new(ShaHasher::default(), 64));
+                         fill(&mut tree);
+                         black_box(tree);
+                     }));
+}
+
+criterion_group!(benches, mt_memo_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::{Hasher, ShaHasher};
+
+/// Building a full level 20 tree, serial `from_leaves` against `from_leaves_parallel`
+fn mt_parallel_benchmark(c: &mut Criterion) {
+    let levels = 20;
+    let leaves: Vec<Hash> = (0..MerkleTree::capacity_for(levels)).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+
+    let mut group = c.benchmark_group(format!("MT: {}. From leaves", levels));
+    group.sample_size(10);
+    group.bench_function("serial", |b| b.iter(|| black_box(MerkleTree::from_leaves(levels, ShaHasher::default(), &leaves).unwrap())));
+    group.bench_function("parallel", |b| b.iter(|| black_box(MerkleTree::from_leaves_parallel(levels, ShaHasher::default(), &leaves).unwrap())));
+    group.finish();
+}
+
+criterion_group!(benches, mt_parallel_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::ShaHasher;
+use merkletree::merkletree::{verify_multiproof, verify_proof};
+
+/// Verifying `N` leaves of a full level 20 tree one proof at a time and with one multiproof
+fn mt_proof_verify_benchmark(c: &mut Criterion) {
+    let levels = 20;
+    let mut tree = MerkleTree::new(levels, ShaHasher::default());
+    tree.begin_defer();
+    for i in 0..tree.capacity() {
+        tree.add(tree.generate_hash(&i.to_be_bytes()));
+    }
+    tree.commit();
+    let root = tree.root_hash_or_empty();
+
+    for &n in &[16u32, 256] {
+        // the first n leaves share most of their upper path, the case multiproofs help with
+        let proofs: Vec<(Hash, Vec<(Hash, bool)>)> = (0..n)
+            .map(|i| (tree.generate_hash(&i.to_be_bytes()), tree.proof(i).unwrap().path()))
+            .collect();
+
+        let id = format!("MT: {}. Verify {} single proofs.", levels, n);
+        c.bench_function(id.as_str(),
+                         |b| b.iter(|| {
+                             for (i, (leaf, proof)) in proofs.iter().enumerate() {
+                                 assert!(verify_proof(leaf, i as u32, tree.size(), black_box(proof), &root, tree.hasher()));
+                             }
+                         }));
+
+        let indexes: Vec<u32> = (0..n).collect();
+        let leaves: Vec<Hash> = proofs.iter().map(|(leaf, _)| *leaf).collect();
+        let multiproof = tree.multiproof(&indexes).unwrap();
+
+        let id = format!("MT: {}. Verify {} leaves with a multiproof.", levels, n);
+        c.bench_function(id.as_str(),
+                         |b| b.iter(|| {
+                             assert!(verify_multiproof(&leaves, &indexes, black_box(&multiproof), &root, tree.hasher()));
+                         }));
+    }
+}
+
+criterion_group!(benches, mt_proof_verify_benchmark);
+criterion_main!(benches);
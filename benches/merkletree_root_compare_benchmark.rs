@@ -0,0 +1,49 @@
+use std::convert::TryInto;
+
+use criterion::{black_box, Criterion, criterion_group, criterion_main};
+
+use merkletree::{Hash, MerkleTree};
+use merkletree::hash::ShaHasher;
+use merkletree::merkletree::verify_proof;
+
+fn words(hash: &Hash) -> [u64; 4] {
+    let mut words = [0u64; 4];
+    for (word, chunk) in words.iter_mut().zip(hash.chunks_exact(8)) {
+        *word = u64::from_ne_bytes(chunk.try_into().unwrap());
+    }
+    words
+}
+
+/// The final root comparison of `verify_proof`, byte-wise `[u8; 32]` against word-wise `[u64; 4]`,
+/// next to a whole level 20 `verify_proof` to show what share of it the comparison is
+fn mt_root_compare_benchmark(c: &mut Criterion) {
+    let levels = 20;
+    let mut tree = MerkleTree::new(levels, ShaHasher::default());
+    tree.begin_defer();
+    for i in 0..tree.capacity() {
+        tree.add(tree.generate_hash(&i.to_be_bytes()));
+    }
+    tree.commit();
+    let root = tree.root_hash_or_empty();
+
+    // differing only in the last byte, the worst case for an early exit
+    let mut other = root;
+    other[31] ^= 1;
+
+    c.bench_function("Root compare. Byte-wise.",
+                     |b| b.iter(|| black_box(&root) == black_box(&other)));
+
+    c.bench_function("Root compare. Word-wise.",
+                     |b| b.iter(|| words(black_box(&root)) == words(black_box(&other))));
+
+    let leaf = tree.generate_hash(&7u32.to_be_bytes());
+    let proof = tree.proof(7).unwrap().path();
+    let id = format!("MT: {}. Verify one proof.", levels);
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         assert!(verify_proof(&leaf, 7, tree.size(), black_box(&proof), &root, tree.hasher()));
+                     }));
+}
+
+criterion_group!(benches, mt_root_compare_benchmark);
+criterion_main!(benches);
@@ -1,9 +1,7 @@
 use criterion::{black_box, Criterion, criterion_group, criterion_main};
 use merkletree::{Hash, MerkleTree};
 use merkletree::hash::ShaHasher;
-use rand::Rng;
 use rand::seq::SliceRandom;
-use std::time::Instant;
 
 fn mt_update_benchmark(c: &mut Criterion) {
     let levels = 20;
@@ -11,7 +9,7 @@ fn mt_update_benchmark(c: &mut Criterion) {
     println!("empty: {}", tree);
 
     let mut k = 0;
-    let nodes_size = 1 << levels - 1;
+    let nodes_size = 1 << (levels - 1);
     let mut time = std::time::Instant::now();
     while k < nodes_size {
         let hash = tree.generate_hash("hello".as_bytes());
@@ -45,6 +43,16 @@ fn mt_update_benchmark(c: &mut Criterion) {
                              };
                          }
                      }));
+
+    let updates: Vec<(u32, Hash)> = indexes.iter().map(|i| (*i, gen_hashes[*i as usize])).collect();
+    let id = format!("MT: {}. Update many: {}", tree.capacity(), test_size);
+
+    c.bench_function(id.as_str(),
+                     |b| b.iter(|| {
+                         if let Err(e) = tree.update_many(black_box(&updates)) {
+                             panic!("Update failed. {:?}", e)
+                         }
+                     }));
 }
 
 fn prepare_hashes(n: u32, tree: &MerkleTree) -> Vec<Hash> {
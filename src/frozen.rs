@@ -0,0 +1,143 @@
+use alloc::{boxed::Box, string::String, vec::Vec};
+use anyhow::{bail, Result};
+use core::convert::TryInto;
+
+use crate::merkletree::MerkleTreeError;
+use crate::{Hash, MerkleTree, Proof};
+
+/// Read-only tree restored from `MerkleTree::into_frozen_bytes`.
+///
+/// Layout (big endian): `levels: u8 | size: u32 | root: u32 | id_len: u8 | id | nodes`,
+/// where `nodes` is the whole array with empty slots stored as the empty subtree hash of their height.
+pub struct FrozenMerkleTree {
+    algorithm_id: String,
+    levels: u32,
+    size: u32,
+    root: u32,
+    nodes: Box<[Hash]>,
+}
+
+impl FrozenMerkleTree {
+    const HEADER_SIZE: usize = 1 + 4 + 4 + 1;
+
+    pub(crate) fn encode<'a>(levels: u32, size: u32, root: u32, algorithm_id: &str,
+                             nodes: impl ExactSizeIterator<Item=&'a Hash>) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::HEADER_SIZE + algorithm_id.len() + nodes.len() * MerkleTree::HASH_SIZE_BYTES);
+        bytes.push(levels as u8);
+        bytes.extend_from_slice(&size.to_be_bytes());
+        bytes.extend_from_slice(&root.to_be_bytes());
+        bytes.push(algorithm_id.len() as u8);
+        bytes.extend_from_slice(algorithm_id.as_bytes());
+        for node in nodes {
+            bytes.extend_from_slice(node);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < Self::HEADER_SIZE {
+            bail!(MerkleTreeError::FrozenFormatError)
+        }
+
+        let levels = bytes[0] as u32;
+        let size = u32::from_be_bytes(bytes[1..5].try_into()?);
+        let root = u32::from_be_bytes(bytes[5..9].try_into()?);
+        let id_len = bytes[9] as usize;
+
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) || bytes.len() < Self::HEADER_SIZE + id_len {
+            bail!(MerkleTreeError::FrozenFormatError)
+        }
+
+        let algorithm_id = String::from_utf8(bytes[Self::HEADER_SIZE..Self::HEADER_SIZE + id_len].to_vec())?;
+        let body = &bytes[Self::HEADER_SIZE + id_len..];
+
+        let nodes_size = (1usize << levels) - 1;
+        if body.len() != nodes_size * MerkleTree::HASH_SIZE_BYTES || size > MerkleTree::capacity_for(levels) {
+            bail!(MerkleTreeError::FrozenFormatError)
+        }
+        // the floating root of `size` leaves, anything else would have proofs climb past node 0
+        if root != (1 << (levels - 1 - MerkleTree::depth_for(size))) - 1 {
+            bail!(MerkleTreeError::FrozenFormatError)
+        }
+
+        let nodes = body.chunks_exact(MerkleTree::HASH_SIZE_BYTES)
+            .map(|chunk| chunk.try_into().unwrap())
+            .collect::<Vec<Hash>>()
+            .into_boxed_slice();
+
+        Ok(FrozenMerkleTree { algorithm_id, levels, size, root, nodes })
+    }
+
+    pub fn algorithm_id(&self) -> &str {
+        &self.algorithm_id
+    }
+
+    pub fn levels(&self) -> u32 {
+        self.levels
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn hash_of(&self, index: usize) -> Hash {
+        self.nodes[index]
+    }
+
+    pub fn root_hash(&self) -> Hash {
+        self.nodes[self.root as usize]
+    }
+
+    /// same `Proof` as `MerkleTree::proof` on the tree this was frozen from
+    pub fn proof(&self, index: u32) -> Result<Proof> {
+        if index >= self.size {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+
+        let mut node = index + (self.nodes.len() as u32 - 1) / 2;
+        let leaf = self.nodes[node as usize];
+        let mut siblings = Vec::new();
+        while node != self.root {
+            let parent = MerkleTree::parent(node).ok_or(MerkleTreeError::FrozenFormatError)?;
+            siblings.push(self.nodes[MerkleTree::sibling(node) as usize]);
+            node = parent;
+        }
+        Ok(Proof { leaf, index, siblings, size: self.size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::ShaHasher;
+
+    use super::*;
+
+    #[test]
+    fn frozen_bytes_rejects_truncated_input() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        let bytes = tree.into_frozen_bytes();
+
+        assert!(FrozenMerkleTree::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(FrozenMerkleTree::from_bytes(&bytes[..4]).is_err());
+    }
+
+    #[test]
+    fn frozen_bytes_rejects_root_off_the_floating_root() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        for i in 0..3u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let mut bytes = tree.into_frozen_bytes();
+        assert!(FrozenMerkleTree::from_bytes(&bytes).is_ok());
+
+        bytes[5..9].copy_from_slice(&2u32.to_be_bytes());
+        let err = FrozenMerkleTree::from_bytes(&bytes).err().unwrap();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::FrozenFormatError)));
+
+        bytes[5..9].copy_from_slice(&0u32.to_be_bytes());
+        let mut frozen = FrozenMerkleTree::from_bytes(&bytes).unwrap();
+        frozen.root = 2;
+        assert!(frozen.proof(0).is_err());
+    }
+}
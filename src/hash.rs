@@ -1,47 +1,671 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::convert::TryInto;
+#[cfg(feature = "std")]
+use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
 use tiny_keccak::Sha3;
 
 use crate::{Hash, MerkleTree};
 
-pub trait Hasher {
-    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash;
+/// Hash function of a tree with `N` byte nodes.
+///
+/// `Send + Sync` so a built tree can sit behind an `Arc` and serve proofs from many threads.
+/// hashers keeping state must synchronize it themselves, as `MemoHasher` does with a `Mutex`
+pub trait Hasher<const N: usize = 32>: Send + Sync {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash<N>;
 
-    fn generate_hash(&self, data: &[u8]) -> Hash;
+    fn generate_hash(&self, data: &[u8]) -> Hash<N>;
+
+    /// `concat_hash` without the hasher's domain tag, to reproduce roots built before tagging.
+    /// hashers that don't tag have nothing to strip
+    fn concat_hash_raw(&self, left: &[u8], right: &[u8]) -> Hash<N> {
+        self.concat_hash(left, right)
+    }
+
+    /// `generate_hash` without the hasher's domain tag, see `concat_hash_raw`
+    fn generate_hash_raw(&self, data: &[u8]) -> Hash<N> {
+        self.generate_hash(data)
+    }
+
+    /// whether `expected_parent` is `concat_hash(left, right)`. hashers may override it
+    /// to short-circuit or compare in constant time
+    fn verify_pair(&self, left: &Hash<N>, right: &Hash<N>, expected_parent: &Hash<N>) -> bool {
+        &self.concat_hash(left, right) == expected_parent
+    }
+
+    /// stable name of the hash function, persisted next to serialized trees
+    fn algorithm_id(&self) -> &'static str {
+        "unknown"
+    }
 }
 
+/// SHA3-256 with RFC 6962 domain separation: leaf data is hashed as `0x00 || data` and children
+/// as `0x01 || left || right`, so no leaf value can pass for an internal node.
+/// the `_raw` methods skip the tag, see `Untagged`
+#[derive(Default)]
 pub struct ShaHasher {}
 
 impl ShaHasher {
+    const LEAF_TAG: u8 = 0x00;
+    const NODE_TAG: u8 = 0x01;
+
     const fn zero() -> Hash {
         [0; MerkleTree::HASH_SIZE_BYTES]
     }
-}
 
-impl Default for ShaHasher {
-    fn default() -> Self {
-        ShaHasher {}
+    /// the usual tag and pair of 32-byte children is copied into one stack buffer and absorbed with
+    /// a single `update`, see `mt_concat_benchmark`. longer inputs (e.g. salted) are fed in parts
+    fn sha3(tag: &[u8], left: &[u8], right: &[u8]) -> Hash {
+        use tiny_keccak::Hasher;
+
+        let mut sha = Sha3::v256();
+        let len = tag.len() + left.len() + right.len();
+        if len <= 1 + 2 * MerkleTree::HASH_SIZE_BYTES {
+            let mut buffer = [0u8; 1 + 2 * MerkleTree::HASH_SIZE_BYTES];
+            buffer[..tag.len()].copy_from_slice(tag);
+            buffer[tag.len()..tag.len() + left.len()].copy_from_slice(left);
+            buffer[tag.len() + left.len()..len].copy_from_slice(right);
+            sha.update(&buffer[..len]);
+        } else {
+            sha.update(tag);
+            sha.update(left);
+            sha.update(right);
+        }
+        let mut hash = Self::zero();
+        sha.finalize(&mut hash[..]);
+        hash
     }
 }
 
 impl Hasher for ShaHasher {
     fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        Self::sha3(&[Self::NODE_TAG], left, right)
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        Self::sha3(&[Self::LEAF_TAG], data, &[])
+    }
+
+    fn concat_hash_raw(&self, left: &[u8], right: &[u8]) -> Hash {
+        Self::sha3(&[], left, right)
+    }
+
+    fn generate_hash_raw(&self, data: &[u8]) -> Hash {
+        Self::sha3(&[], data, &[])
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        "sha3-256"
+    }
+}
+
+/// Hashes with the inner hasher's `_raw` methods, e.g. `Untagged::new(ShaHasher::default())`
+/// rebuilds roots of trees made before `ShaHasher` tagged leaves and nodes. reports the inner id
+pub struct Untagged<H: Hasher> {
+    inner: H,
+}
+
+impl<H: Hasher> Untagged<H> {
+    pub fn new(inner: H) -> Self {
+        Untagged { inner }
+    }
+}
+
+impl<H: Hasher> Hasher for Untagged<H> {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        self.inner.concat_hash_raw(left, right)
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        self.inner.generate_hash_raw(data)
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        self.inner.algorithm_id()
+    }
+}
+
+/// BLAKE3 with its default 32-byte output, usually faster than SHA3 on large builds
+#[cfg(feature = "blake3")]
+#[derive(Default)]
+pub struct Blake3Hasher {}
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(left);
+        hasher.update(right);
+        *hasher.finalize().as_bytes()
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        *blake3::hash(data).as_bytes()
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        "blake3"
+    }
+}
+
+/// SHA-256 applied once, to leaf data and to `left || right` alike
+#[cfg(feature = "sha2")]
+#[derive(Default)]
+pub struct Sha256Hasher {}
+
+#[cfg(feature = "sha2")]
+impl Hasher for Sha256Hasher {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        let mut sha = sha2::Sha256::new();
+        sha.update(left);
+        sha.update(right);
+        sha.finalize().into()
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(data).into()
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        "sha256"
+    }
+}
+
+/// Bitcoin style double SHA-256, `sha256(sha256(x))`, for leaves and for `left || right`.
+/// bitcoin displays txids and roots byte reversed, see `EndianHasher` for matching those
+#[cfg(feature = "sha2")]
+#[derive(Default)]
+pub struct Sha256dHasher {}
+
+#[cfg(feature = "sha2")]
+impl Hasher for Sha256dHasher {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(Sha256Hasher::default().concat_hash(left, right)).into()
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        use sha2::Digest;
+
+        sha2::Sha256::digest(Sha256Hasher::default().generate_hash(data)).into()
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        "sha256d"
+    }
+}
+
+/// 32-byte hasher whose `algorithm_id` is `name`, for picking the hash function at runtime
+/// (e.g. from config or the id stored next to a serialized tree). feature gated hashers are
+/// only found when their feature is enabled
+pub fn hasher_by_name(name: &str) -> Option<Box<dyn Hasher>> {
+    match name {
+        "sha3-256" => Some(Box::new(ShaHasher::default())),
+        #[cfg(feature = "blake3")]
+        "blake3" => Some(Box::new(Blake3Hasher::default())),
+        #[cfg(feature = "sha2")]
+        "sha256" => Some(Box::new(Sha256Hasher::default())),
+        #[cfg(feature = "sha2")]
+        "sha256d" => Some(Box::new(Sha256dHasher::default())),
+        _ => None,
+    }
+}
+
+/// Hash function producing digests longer than `MerkleTree::HASH_SIZE_BYTES`, see `Truncating`.
+pub trait WideHasher: Send + Sync {
+    fn concat_digest(&self, left: &[u8], right: &[u8]) -> Vec<u8>;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+
+    /// id reported by `Truncating` over this hasher
+    fn truncated_algorithm_id(&self) -> &'static str {
+        "unknown"
+    }
+}
+
+#[derive(Default)]
+pub struct Sha3_512Hasher {}
+
+impl WideHasher for Sha3_512Hasher {
+    fn concat_digest(&self, left: &[u8], right: &[u8]) -> Vec<u8> {
         use tiny_keccak::Hasher;
 
-        let mut sha = Sha3::v256();
+        let mut sha = Sha3::v512();
         sha.update(left);
         sha.update(right);
-        let mut hash = Self::zero();
-        sha.finalize(&mut hash[..]);
+        let mut hash = vec![0u8; 64];
+        sha.finalize(&mut hash);
         hash
     }
 
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        self.concat_digest(data, &[])
+    }
+
+    fn truncated_algorithm_id(&self) -> &'static str {
+        "sha3-512-trunc256"
+    }
+}
+
+/// full 64-byte digests, for `MerkleTree<64>`
+impl Hasher<64> for Sha3_512Hasher {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash<64> {
+        self.concat_digest(left, right).try_into().unwrap()
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash<64> {
+        self.digest(data).try_into().unwrap()
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        "sha3-512"
+    }
+}
+
+/// Fits a wider digest into the tree by keeping its first `HASH_SIZE_BYTES` bytes.
+///
+/// A truncated digest is only as strong as its length: collision resistance drops to 128 bits,
+/// the same as a native 256-bit hash. Truncated values never match the native 256-bit variant
+/// of the same family (e.g. SHA3-512 truncated is not SHA3-256).
+pub struct Truncating<H: WideHasher> {
+    inner: H,
+}
+
+impl<H: WideHasher> Truncating<H> {
+    pub fn new(inner: H) -> Self {
+        Truncating { inner }
+    }
+
+    fn truncate(digest: Vec<u8>) -> Hash {
+        digest[..MerkleTree::HASH_SIZE_BYTES].try_into().expect("digest shorter than HASH_SIZE_BYTES")
+    }
+}
+
+impl<H: WideHasher> Hasher for Truncating<H> {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        Self::truncate(self.inner.concat_digest(left, right))
+    }
+
     fn generate_hash(&self, data: &[u8]) -> Hash {
-        use tiny_keccak::Hasher;
+        Self::truncate(self.inner.digest(data))
+    }
 
-        let mut sha = Sha3::v256();
-        sha.update(data);
-        let mut hash = Self::zero();
-        sha.finalize(&mut hash[..]);
+    fn algorithm_id(&self) -> &'static str {
+        self.inner.truncated_algorithm_id()
+    }
+}
+
+/// Prefixes a fixed salt to every hash input, so the same leaves commit to different roots
+/// in differently salted trees. The salt only separates tree instances and may be public;
+/// it is not a secret key and gives no protection against anyone recomputing the tree.
+pub struct SaltedHasher<H: Hasher> {
+    inner: H,
+    salt: Hash,
+}
+
+impl<H: Hasher> SaltedHasher<H> {
+    pub fn new(inner: H, salt: Hash) -> Self {
+        SaltedHasher { inner, salt }
+    }
+}
+
+impl<H: Hasher> Hasher for SaltedHasher<H> {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        let mut salted = Vec::with_capacity(self.salt.len() + left.len());
+        salted.extend_from_slice(&self.salt);
+        salted.extend_from_slice(left);
+        self.inner.concat_hash(&salted, right)
+    }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        let mut salted = Vec::with_capacity(self.salt.len() + data.len());
+        salted.extend_from_slice(&self.salt);
+        salted.extend_from_slice(data);
+        self.inner.generate_hash(&salted)
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        self.inner.algorithm_id()
+    }
+}
+
+/// Byte order child hashes are fed to the inner hasher in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    /// children are hashed as stored. matches Ethereum and most non-Bitcoin trees
+    Big,
+    /// stored hashes are treated as display order (as Bitcoin RPC prints txids): each child is reversed
+    /// into internal byte order before hashing and the digest is reversed back for storing
+    Little,
+}
+
+pub struct EndianHasher<H: Hasher> {
+    inner: H,
+    endianness: Endianness,
+}
+
+impl<H: Hasher> EndianHasher<H> {
+    pub fn new(inner: H, endianness: Endianness) -> Self {
+        EndianHasher { inner, endianness }
+    }
+}
+
+impl<H: Hasher> Hasher for EndianHasher<H> {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        match self.endianness {
+            Endianness::Big => self.inner.concat_hash(left, right),
+            Endianness::Little => {
+                let left: Vec<u8> = left.iter().rev().copied().collect();
+                let right: Vec<u8> = right.iter().rev().copied().collect();
+                let mut hash = self.inner.concat_hash(&left, &right);
+                hash.reverse();
+                hash
+            }
+        }
+    }
+
+    /// leaf data is hashed as is, leaf bytes order is up to the caller
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        self.inner.generate_hash(data)
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        self.inner.algorithm_id()
+    }
+}
+
+/// Remembers the last `capacity` internal node results, keyed by the children pair.
+///
+/// Only pays off for trees with many duplicate pairs (repeated leaf values, large empty regions):
+/// every call takes a lock and a map lookup, and the cache holds ~100 bytes per entry.
+/// Leaf hashing and pairs that aren't exactly two hashes are passed through.
+#[cfg(feature = "std")]
+pub struct MemoHasher<H: Hasher> {
+    inner: H,
+    capacity: usize,
+    cache: Mutex<MemoCache>,
+}
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct MemoCache {
+    tick: u64,
+    entries: HashMap<(Hash, Hash), (Hash, u64)>,
+    // usage order, may hold stale ticks of entries used again later. compacted past twice the capacity
+    order: VecDeque<((Hash, Hash), u64)>,
+}
+
+#[cfg(feature = "std")]
+impl<H: Hasher> MemoHasher<H> {
+    pub fn new(inner: H, capacity: usize) -> Self {
+        MemoHasher { inner, capacity, cache: Mutex::new(MemoCache::default()) }
+    }
+
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+}
+
+#[cfg(feature = "std")]
+impl MemoCache {
+    fn get(&mut self, key: &(Hash, Hash), capacity: usize) -> Option<Hash> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = tick;
+        let value = *value;
+        self.order.push_back((*key, tick));
+
+        // hits only ever append, drop the stale ticks before they outgrow the entries
+        if self.order.len() > 2 * capacity {
+            let entries = &self.entries;
+            self.order.retain(|(key, tick)| entries.get(key).map(|(_, used)| *used) == Some(*tick));
+        }
+        Some(value)
+    }
+
+    fn put(&mut self, key: (Hash, Hash), value: Hash, capacity: usize) {
+        while self.entries.len() >= capacity {
+            let (oldest, tick) = match self.order.pop_front() {
+                Some(o) => o,
+                None => break,
+            };
+            if self.entries.get(&oldest).map(|(_, used)| *used) == Some(tick) {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.entries.insert(key, (value, self.tick));
+        self.order.push_back((key, self.tick));
+    }
+}
+
+#[cfg(feature = "std")]
+impl<H: Hasher> Hasher for MemoHasher<H> {
+    fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+        let key = match (left.try_into(), right.try_into()) {
+            (Ok(l), Ok(r)) if self.capacity > 0 => (l, r),
+            _ => return self.inner.concat_hash(left, right),
+        };
+
+        if let Some(hash) = self.cache.lock().unwrap().get(&key, self.capacity) {
+            return hash;
+        }
+
+        let hash = self.inner.concat_hash(left, right);
+        self.cache.lock().unwrap().put(key, hash, self.capacity);
         hash
     }
+
+    fn generate_hash(&self, data: &[u8]) -> Hash {
+        self.inner.generate_hash(data)
+    }
+
+    fn algorithm_id(&self) -> &'static str {
+        self.inner.algorithm_id()
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_buffer_concat_matches_two_updates() {
+        use tiny_keccak::Hasher as _;
+
+        let hasher = ShaHasher::default();
+        for (left, right) in [(vec![1u8; 32], vec![2u8; 32]), (vec![3u8; 5], vec![4u8; 7]), (vec![5u8; 64], vec![6u8; 32]), (vec![], vec![])] {
+            for tag in [&[ShaHasher::NODE_TAG][..], &[]] {
+                let mut sha = Sha3::v256();
+                sha.update(tag);
+                sha.update(&left);
+                sha.update(&right);
+                let mut expected = [0u8; 32];
+                sha.finalize(&mut expected);
+
+                let hash = if tag.is_empty() { hasher.concat_hash_raw(&left, &right) } else { hasher.concat_hash(&left, &right) };
+                assert_eq!(hash, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn leaf_equal_to_children_concatenation_is_not_their_parent() {
+        let hasher = ShaHasher::default();
+        let left = hasher.generate_hash("left".as_bytes());
+        let right = hasher.generate_hash("right".as_bytes());
+        let concatenated = [left, right].concat();
+
+        assert_ne!(hasher.generate_hash(&concatenated), hasher.concat_hash(&left, &right));
+        assert_eq!(hasher.generate_hash_raw(&concatenated), hasher.concat_hash_raw(&left, &right));
+
+        let salted = SaltedHasher::new(ShaHasher::default(), [7u8; 32]);
+        assert_ne!(salted.generate_hash(&concatenated), salted.concat_hash(&left, &right));
+
+        let legacy = Untagged::new(ShaHasher::default());
+        assert_eq!(legacy.concat_hash(&left, &right), hasher.concat_hash_raw(&left, &right));
+    }
+
+    #[test]
+    fn verify_pair_matches_concat_hash() {
+        let hasher = ShaHasher::default();
+        let left = hasher.generate_hash("left".as_bytes());
+        let right = hasher.generate_hash("right".as_bytes());
+        let parent = hasher.concat_hash(&left, &right);
+
+        assert!(hasher.verify_pair(&left, &right, &parent));
+        assert!(!hasher.verify_pair(&right, &left, &parent));
+        assert!(!hasher.verify_pair(&left, &right, &left));
+    }
+
+    #[test]
+    fn salted_hashers_give_different_roots() {
+        let build = |salt: Hash| {
+            let mut tree = MerkleTree::new(3, SaltedHasher::new(ShaHasher::default(), salt));
+            for i in 0..4u32 {
+                tree.add(tree.generate_hash(&i.to_be_bytes()));
+            }
+            tree.hash_of(0).unwrap()
+        };
+
+        assert_eq!(build([1; 32]), build([1; 32]));
+        assert_ne!(build([1; 32]), build([2; 32]));
+    }
+
+    #[test]
+    fn memo_hasher_matches_plain_hasher() {
+        let build = |tree: &mut MerkleTree| {
+            for i in 0..16u32 {
+                tree.add(tree.generate_hash(&(i % 3).to_be_bytes()));
+            }
+            tree.hash_of(0).unwrap()
+        };
+
+        let memo = MemoHasher::new(ShaHasher::default(), 4);
+        assert_eq!(memo.concat_hash(&[1u8; 32], &[2u8; 32]), ShaHasher::default().concat_hash(&[1u8; 32], &[2u8; 32]));
+        assert_eq!(memo.cached(), 1);
+
+        let mut plain = MerkleTree::new(5, ShaHasher::default());
+        let mut memoized = MerkleTree::new(5, MemoHasher::new(ShaHasher::default(), 4));
+        assert_eq!(build(&mut plain), build(&mut memoized));
+    }
+
+    #[test]
+    fn memo_cache_evicts_least_recently_used() {
+        let memo = MemoHasher::new(ShaHasher::default(), 2);
+        let (a, b, c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+        memo.concat_hash(&a, &a);
+        memo.concat_hash(&b, &b);
+        memo.concat_hash(&a, &a);
+        memo.concat_hash(&c, &c);
+
+        let cache = memo.cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.entries.contains_key(&(a, a)));
+        assert!(!cache.entries.contains_key(&(b, b)));
+    }
+
+    #[test]
+    fn memo_cache_order_stays_bounded_under_hits() {
+        let memo = MemoHasher::new(ShaHasher::default(), 4);
+        let pairs: Vec<Hash> = (0..4u8).map(|i| [i; 32]).collect();
+        for _ in 0..1000 {
+            for pair in &pairs {
+                memo.concat_hash(pair, pair);
+            }
+        }
+
+        let cache = memo.cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), 4);
+        assert!(cache.order.len() <= 2 * 4);
+        drop(cache);
+
+        // recency survives compaction: after touching 1..4 again, 0 is the one evicted
+        for pair in &pairs[1..] {
+            memo.concat_hash(pair, pair);
+        }
+        memo.concat_hash(&[9u8; 32], &[9u8; 32]);
+        let cache = memo.cache.lock().unwrap();
+        assert!(!cache.entries.contains_key(&(pairs[0], pairs[0])));
+        assert!(cache.entries.contains_key(&(pairs[1], pairs[1])));
+    }
+
+    #[test]
+    fn truncating_keeps_leading_bytes() {
+        let reference = {
+            use tiny_keccak::Hasher;
+
+            let mut sha = Sha3::v512();
+            sha.update("hello".as_bytes());
+            let mut reference = [0u8; 64];
+            sha.finalize(&mut reference);
+            reference
+        };
+
+        let hasher = Truncating::new(Sha3_512Hasher::default());
+        let leaf = hasher.generate_hash("hello".as_bytes());
+        assert_eq!(&leaf[..], &reference[..32]);
+        assert_eq!(leaf, hasher.generate_hash("hello".as_bytes()));
+        assert_ne!(leaf, ShaHasher::default().generate_hash("hello".as_bytes()));
+
+        let mut tree = MerkleTree::new(2, hasher);
+        tree.add(leaf);
+        tree.add(leaf);
+        assert_eq!(tree.hasher().algorithm_id(), "sha3-512-trunc256");
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_concat_hashes_joined_input() {
+        let hasher = Blake3Hasher::default();
+        assert_eq!(hasher.concat_hash(b"hel", b"lo"), hasher.generate_hash(b"hello"));
+        assert_eq!(hasher.generate_hash(b""), *blake3::hash(b"").as_bytes());
+        assert_ne!(hasher.generate_hash(b"hello"), ShaHasher::default().generate_hash(b"hello"));
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn sha256_single_and_double() {
+        let single = Sha256Hasher::default();
+        assert_eq!(hex::encode(single.generate_hash(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(single.concat_hash(b"a", b"bc"), single.generate_hash(b"abc"));
+
+        let double = Sha256dHasher::default();
+        assert_eq!(hex::encode(double.generate_hash(b"")), "5df6e0e2761359d30a8275058e299fcc0381534545f55cf43e41983f5d4c9456");
+        assert_eq!(double.concat_hash(b"a", b"bc"), double.generate_hash(b"abc"));
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn little_endian_sha256d_matches_bitcoin_block_root() {
+        // block 100000, txids and merkle root as printed by bitcoin-cli
+        let txids = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ];
+        let root = "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766";
+
+        let build = |endianness| {
+            let mut tree = MerkleTree::new(3, EndianHasher::new(Sha256dHasher::default(), endianness));
+            for txid in &txids {
+                tree.add(hex::decode(txid).unwrap().as_slice().try_into().unwrap());
+            }
+            tree
+        };
+
+        let tree = build(Endianness::Little);
+        assert_eq!(hex::encode(tree.root_hash().unwrap()), root);
+        assert!(tree.proof(2).unwrap().verify(&tree.root_hash().unwrap(), tree.hasher()));
+        assert_ne!(hex::encode(build(Endianness::Big).root_hash().unwrap()), root);
+    }
+}
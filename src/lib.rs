@@ -1,9 +1,54 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, unused_variables))]
 
-/// Merkle tree array based version with floating root point
+//! Merkle tree array based version with floating root point
+//!
+//! Without the default `std` feature the crate is `no_std` on `alloc`. `save_leaves`/`load_leaves`,
+//! `MemoHasher` and `MerkleTreeError::Io` need `std`, and errors display as their variant names.
 
-pub use crate::merkletree::{Hash, MerkleTree, OptionHash};
+extern crate alloc;
 
+pub use crate::frozen::FrozenMerkleTree;
+pub use crate::merkletree::{Appended, AuditReport, Hash, MerkleTree, MerkleTree256, MerkleTreeError, Neighbor, NonMembershipProof, OptionHash, ProofBuilder, VerifyOutcome, ZeroLeafPolicy};
+#[cfg(feature = "serde")]
+pub use crate::merkletree::MerkleTreeState;
+pub use crate::mirror::TreeMirror;
+pub use crate::nodes::PackedNodes;
+pub use crate::observer::TreeObserver;
+pub use crate::persistent::PersistentMerkleTree;
+pub use crate::proof::{CombinedProof, DetailedProof, MultiProof, Proof};
+pub use crate::stack::StackMerkleTree;
+
+pub mod frozen;
 pub mod hash;
 pub mod merkletree;
+pub mod mirror;
+pub mod nodes;
+pub mod observer;
+pub mod persistent;
+pub mod proof;
+pub mod stack;
+
+/// `use merkletree::prelude::*;` brings in everything needed to build and check a tree
+pub mod prelude {
+    pub use crate::{Appended, FrozenMerkleTree, Hash, MerkleTree, MerkleTreeError, OptionHash, PersistentMerkleTree};
+    pub use crate::hash::{Hasher, ShaHasher};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    #[test]
+    fn prelude_is_enough_to_build_and_verify() {
+        let hasher = ShaHasher::default();
+        let leaves: Vec<Hash> = (0..3u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+
+        let mut tree = MerkleTree::new(3, hasher);
+        for leaf in &leaves {
+            tree.add(*leaf);
+        }
 
+        assert!(tree.verify_leaves(&leaves).unwrap());
+    }
+}
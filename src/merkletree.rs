@@ -1,31 +1,143 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
 use anyhow::{bail, Result};
+use core::convert::TryInto;
+use core::fmt::{Debug, Display, Formatter};
+use core::iter::FromIterator;
 use hex;
 use log::{debug, error, info, Level, log_enabled};
-use std::fmt::{Debug, Display, Formatter};
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
 use thiserror::Error;
 
-use crate::hash::Hasher;
+use crate::frozen::FrozenMerkleTree;
+use crate::hash::{Hasher, ShaHasher};
+use crate::nodes::PackedNodes;
+use crate::observer::TreeObserver;
+use crate::persistent::PersistentMerkleTree;
+use crate::proof::{CombinedProof, DetailedProof, MultiProof, Proof};
 
-pub type Hash = [u8; MerkleTree::HASH_SIZE_BYTES];
-pub type OptionHash = Option<Hash>;
+#[cfg(feature = "serde")]
+pub use self::state::MerkleTreeState;
 
-pub struct MerkleTree {
-    hasher: Box<dyn Hasher + 'static>,
+mod proof_cache;
+#[cfg(feature = "serde")]
+mod state;
+
+/// tree node value. `N` defaults to `MerkleTree::HASH_SIZE_BYTES`
+pub type Hash<const N: usize = 32> = [u8; N];
+pub type OptionHash<const N: usize = 32> = Option<Hash<N>>;
+
+#[cfg(feature = "std")]
+const LEAVES_MAGIC: &[u8; 4] = b"MTLF";
+#[cfg(feature = "std")]
+const LEAVES_VERSION: u8 = 1;
+#[cfg(feature = "std")]
+const LEAVES_HEADER_SIZE: usize = 4 + 1 + 1 + 1 + 4;
+/// narrower levels are hashed serially by `from_leaves_parallel`, splitting them isn't worth it
+#[cfg(feature = "rayon")]
+const PARALLEL_MIN_LEVEL_WIDTH: usize = 1024;
+
+/// How `MerkleTree::proof` treats leaf slots nothing was written to, see `with_zero_leaf_policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ZeroLeafPolicy {
+    /// unset slots are proven as holding the default hash they fold as, so they can't be told
+    /// apart from a leaf actually set to `empty_root()`
+    #[default]
+    FoldAsDefault,
+    /// only set slots (by occupancy, whatever their value) are members. a leaf equal to
+    /// `empty_root()` is a real, provable member and proving an unset slot fails
+    Occupancy,
+}
+
+/// Result of `add_commit`: where the leaf went and the root committing to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Appended<const N: usize = 32> {
+    pub index: u32,
+    pub root: Hash<N>,
+}
+
+/// `N` is the byte length of every node hash, 32 unless a hasher with another digest size is used
+pub struct MerkleTree<const N: usize = 32> {
+    hasher: Arc<dyn Hasher<N> + 'static>,
+    levels: u32,
     root: u32,
     zero_index: u32,
     current_add_position: usize,
-    nodes: Box<[OptionHash]>,
-    default_hash: Hash,
-    max_size: u32,
+    nodes: PackedNodes<N>,
+    default_hash: Hash<N>,
+    empty_subtrees: Vec<Hash<N>>,
+    current_root: OptionHash<N>,
+    // first leaf added since `begin_defer` whose branch isn't calculated yet
+    deferred_from: Option<usize>,
+    deferring: bool,
+    root_history: VecDeque<(u32, Hash<N>)>,
+    root_history_limit: usize,
+    observer: Option<Box<dyn TreeObserver>>,
+    zero_leaf_policy: ZeroLeafPolicy,
+    // made by `new_sparse`: every slot counts towards `size()` and `clear`/`rebuild` keep it that way
+    sparse: bool,
+    // by logical index, only as long as the last leaf added with metadata
+    metadata: Vec<Option<u64>>,
+    // `with_proof_cache`, by leaf index
+    proof_cache: Option<BTreeMap<u32, Proof<N>>>,
 }
 
+pub type MerkleTree256 = MerkleTree<32>;
 
-impl MerkleTree {
-    pub const HASH_SIZE_BYTES: usize = 32;
 
-    pub fn new(levels: u32, hasher: impl Hasher + 'static) -> Self {
-        if levels < 1 && levels > 27 {
-            panic!("Not acceptable tree size {}. Consider range [1-28]", levels);
+impl<const N: usize> MerkleTree<N> {
+    /// panics if `levels` is outside `[1, MAX_LEVELS]`, see `try_new`
+    pub fn new(levels: u32, hasher: impl Hasher<N> + 'static) -> Self {
+        let default_leaf = hasher.generate_hash(&[0u8; N]);
+        Self::new_with_default(levels, hasher, default_leaf)
+    }
+
+    /// `new` with absent leaves folding as `default_leaf` instead of `generate_hash([0; N])`,
+    /// for protocols that fix their own empty leaf value
+    pub fn new_with_default(levels: u32, hasher: impl Hasher<N> + 'static, default_leaf: Hash<N>) -> Self {
+        Self::with_shared_hasher(levels, Arc::new(hasher), default_leaf)
+    }
+
+    /// tree addressed by leaf index instead of appended to: every one of the `capacity()` slots counts
+    /// as present from the start, unset ones as the default hash. write them with `set`, `add` is
+    /// always full. the root stays on node 0, so proofs of set and unset leaves alike reach it
+    pub fn new_sparse(levels: u32, hasher: impl Hasher<N> + 'static) -> Self {
+        let mut tree = Self::new(levels, hasher);
+        tree.sparse = true;
+        tree.reset_positions();
+        tree
+    }
+
+    fn reset_positions(&mut self) {
+        if self.sparse {
+            self.root = 0;
+            self.current_add_position = self.nodes.len();
+        } else {
+            self.root = self.zero_index;
+            self.current_add_position = self.zero_index as usize;
+        }
+    }
+
+    /// `new` returning `LevelsError` for `levels` outside `[1, MAX_LEVELS]` instead of panicking
+    pub fn try_new(levels: u32, hasher: impl Hasher<N> + 'static) -> Result<Self> {
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) {
+            bail!(MerkleTreeError::LevelsError(levels))
+        }
+        Ok(Self::new(levels, hasher))
+    }
+
+    /// `new` for a hasher already boxed, e.g. by `hash::hasher_by_name`
+    pub fn new_boxed(levels: u32, hasher: Box<dyn Hasher<N>>) -> Self {
+        let default_leaf = hasher.generate_hash(&[0u8; N]);
+        Self::with_shared_hasher(levels, Arc::from(hasher), default_leaf)
+    }
+
+    fn with_shared_hasher(levels: u32, hasher: Arc<dyn Hasher<N>>, default_hash: Hash<N>) -> Self {
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) {
+            panic!("{}", MerkleTreeError::LevelsError(levels));
         }
 
         let nodes_size = (1 << levels) - 1;
@@ -33,256 +145,3310 @@ impl MerkleTree {
         info!("Creating merkle tree with size {}", nodes_size);
 
         let index = ((nodes_size - 1) / 2) as u32;
-        let default_hash = hasher.generate_hash(&[0u8; MerkleTree::HASH_SIZE_BYTES]);
+        let empty_subtrees = Self::empty_subtrees(hasher.as_ref(), default_hash, levels);
 
         MerkleTree {
-            hasher: Box::new(hasher),
+            hasher,
+            levels,
             root: index,
             zero_index: index,
             current_add_position: index as usize,
-            nodes: vec![Option::None::<[u8; Self::HASH_SIZE_BYTES]>; nodes_size].into_boxed_slice(),
+            nodes: PackedNodes::new(nodes_size),
             default_hash,
-            max_size: 1 << levels - 1,
+            empty_subtrees,
+            current_root: None,
+            deferred_from: None,
+            deferring: false,
+            root_history: VecDeque::new(),
+            root_history_limit: 0,
+            observer: None,
+            zero_leaf_policy: ZeroLeafPolicy::FoldAsDefault,
+            sparse: false,
+            metadata: Vec::new(),
+            proof_cache: None,
         }
     }
 
-    pub fn capacity(&self) -> u32 {
-        self.max_size
+    /// builds a tree from hex encoded leaf hashes, `0x` prefix is optional
+    pub fn from_hex_leaves(levels: u32, hasher: impl Hasher<N> + 'static, leaves: &[&str]) -> Result<Self> {
+        let mut tree = Self::new(levels, hasher);
+        if leaves.len() > tree.capacity() as usize {
+            bail!(MerkleTreeError::CapacityError(leaves.len()))
+        }
+
+        for leaf in leaves {
+            let mut hash = [0u8; N];
+            hex::decode_to_slice(leaf.trim_start_matches("0x"), &mut hash).map_err(MerkleTreeError::from)?;
+            tree.add(hash);
+        }
+
+        Ok(tree)
     }
 
-    pub fn hash_of(&self, index: usize) -> OptionHash {
-        self.nodes[index]
+    /// builds a `levels` deep tree from `leaves` in `O(n)`: leaves are placed first and
+    /// every internal node is hashed once, bottom-up, instead of a branch walk per `add`
+    pub fn from_leaves(levels: u32, hasher: impl Hasher<N> + 'static, leaves: &[Hash<N>]) -> Result<Self> {
+        Self::from_leaves_with_levels(leaves, levels, hasher)
     }
 
-    /// returns MT index of added value
-    pub fn add(&mut self, value: Hash) -> u32 {
-        if !(self.max_size > self.size()) {
-            panic!("We full")
+    /// builds a `levels` deep tree holding `leaves`, the rest of its capacity is left for `add`
+    pub fn from_leaves_with_levels(leaves: &[Hash<N>], levels: u32, hasher: impl Hasher<N> + 'static) -> Result<Self> {
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) || leaves.len() > MerkleTree::capacity_for(levels) as usize {
+            bail!(MerkleTreeError::CapacityError(leaves.len()))
         }
 
-        if self.nodes[self.current_add_position].is_some() {
-            panic!("Replacing not allowed for 'add' command");
+        let mut tree = Self::new(levels, hasher);
+        tree.begin_defer();
+        for leaf in leaves {
+            tree.add(*leaf);
         }
+        tree.commit();
 
-        if log_enabled!(Level::Debug) {
-            debug!("Adding {} to i[{}]", Self::to_hex(&value[..3]), self.current_add_position);
+        Ok(tree)
+    }
+
+    /// `from_leaves_with_levels` hashing each interior level in parallel, the root is the same
+    #[cfg(feature = "rayon")]
+    pub fn from_leaves_parallel(levels: u32, hasher: impl Hasher<N> + 'static, leaves: &[Hash<N>]) -> Result<Self> {
+        use rayon::prelude::*;
+
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) || leaves.len() > MerkleTree::capacity_for(levels) as usize {
+            bail!(MerkleTreeError::CapacityError(leaves.len()))
         }
 
-        self.nodes[self.current_add_position] = Some(value);
-        self.current_add_position += 1;
+        let mut tree = Self::new(levels, hasher);
+        tree.begin_defer();
+        for leaf in leaves {
+            tree.add(*leaf);
+        }
+        tree.deferring = false;
 
-        let current_lvl = ((self.size() + 1) as f64).log2() as u32;
-        self.root = (1 << (self.tree_lvl() - current_lvl)) - 1;
+        let (mut from, mut to) = match tree.deferred_from.take() {
+            Some(from) => (from as u32, tree.current_add_position as u32 - 1),
+            None => return Ok(tree),
+        };
 
-        let node = self.current_add_position as u32 - 1;
-        self.update_branch(node);
-        node - self.zero_index
+        // a level's nodes only depend on the level below, so each one is hashed as a whole
+        while from != tree.root {
+            from = MerkleTree::parent(from).unwrap();
+            to = MerkleTree::parent(to).unwrap();
+            let children: Vec<Hash<N>> = (MerkleTree::child_nodes(from).0..=MerkleTree::child_nodes(to).1)
+                .map(|child| tree.node_or_empty(child))
+                .collect();
+
+            let hasher = &*tree.hasher;
+            let parents: Vec<Hash<N>> = if children.len() >= PARALLEL_MIN_LEVEL_WIDTH {
+                children.par_chunks(2).map(|pair| hasher.concat_hash(&pair[0], &pair[1])).collect()
+            } else {
+                children.chunks(2).map(|pair| hasher.concat_hash(&pair[0], &pair[1])).collect()
+            };
+            for (parent, hash) in (from..=to).zip(parents) {
+                tree.nodes.set(parent as usize, hash);
+            }
+        }
+
+        tree.current_root = tree.nodes.get(tree.root as usize);
+        Ok(tree)
+    }
+
+    /// rebuilds a tree from `leaves_map` output. leaves must be keyed `0..n` without gaps
+    pub fn from_leaves_map(map: &BTreeMap<u32, Hash<N>>, levels: u32, hasher: impl Hasher<N> + 'static) -> Result<Self> {
+        let mut tree = Self::new(levels, hasher);
+        if map.len() > tree.capacity() as usize {
+            bail!(MerkleTreeError::CapacityError(map.len()))
+        }
+
+        for (expected, (index, leaf)) in map.iter().enumerate() {
+            if *index as usize != expected {
+                bail!(MerkleTreeError::UpdateIndexError)
+            }
+            tree.add(*leaf);
+        }
+
+        Ok(tree)
+    }
+
+    /// keeps `(size, root)` of the last `limit` appends for `historical_root` lookups
+    pub fn with_root_history(mut self, limit: usize) -> Self {
+        self.root_history_limit = limit;
+        self.root_history = VecDeque::with_capacity(limit);
+        self
+    }
+
+    /// reports adds, updates, proofs and pair hashes to `observer`, e.g. to feed metrics
+    pub fn with_observer(mut self, observer: Box<dyn TreeObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// sets how unset leaf slots are proven, `FoldAsDefault` unless changed
+    pub fn with_zero_leaf_policy(mut self, policy: ZeroLeafPolicy) -> Self {
+        self.zero_leaf_policy = policy;
+        self
+    }
+
+    /// whether leaf `index` was written, by occupancy rather than by its value
+    pub fn is_member(&self, index: u32) -> bool {
+        index < self.size() && self.nodes.is_set((index + self.zero_index) as usize)
+    }
+
+    /// agreed root of a tree without leaves: the same default hash absent leaves fold as.
+    /// depends on the hasher, so verifiers must compare against the value of a matching tree
+    pub fn empty_root(&self) -> Hash<N> {
+        self.default_hash
+    }
+
+    /// hash of the floating root, the node currently covering every added leaf. `None` while empty
+    pub fn root_hash(&self) -> OptionHash<N> {
+        self.cached_root()
+    }
+
+    /// `root_hash` as `0x` prefixed lowercase hex, for logs and API responses
+    pub fn root_hex(&self) -> Option<String> {
+        self.root_hash().map(|root| MerkleTree::hash_as_hex_with_prefix(&root))
     }
 
-    pub fn update(&mut self, index: u32, value: Hash) -> Result<Hash> {
-        let index = (index + self.zero_index) as usize;
+    /// current root, or the empty subtree it stands for when nothing is set: `empty_root` for an
+    /// appended tree, the empty full tree for a `new_sparse` one
+    pub fn root_hash_or_empty(&self) -> Hash<N> {
+        self.root_hash().unwrap_or_else(|| self.node_or_empty(self.root))
+    }
+
+    /// root of an all-empty subtree per height: `[0]` is the default leaf hash and
+    /// `[h]` is `concat_hash([h - 1], [h - 1])`, up to the empty full tree at `[levels - 1]`.
+    /// an absent node of height `h` folds as `[h]` in roots, proofs and audits alike
+    pub fn empty_subtree_hashes(&self) -> &[Hash<N>] {
+        &self.empty_subtrees
+    }
+
+    pub fn capacity(&self) -> u32 {
+        MerkleTree::capacity_for(self.levels)
+    }
+
+    /// bytes allocated for nodes whether populated or not, plus `aux_bytes`
+    pub fn capacity_bytes(&self) -> usize {
+        self.nodes.len() * N + self.nodes.bitmap_bytes() + self.aux_bytes()
+    }
+
+    /// bytes of populated nodes, plus `aux_bytes`. the gap to `capacity_bytes` is what
+    /// a sparsely filled tree pays for its preallocated array
+    pub fn used_bytes(&self) -> usize {
+        self.nodes.occupied() * N + self.nodes.bitmap_bytes() + self.aux_bytes()
+    }
+
+    /// bookkeeping kept next to the nodes: the empty subtree table and root history
+    fn aux_bytes(&self) -> usize {
+        self.empty_subtrees.capacity() * N + self.root_history.capacity() * core::mem::size_of::<(u32, Hash<N>)>()
+    }
+
+    pub fn hash_of(&self, index: usize) -> OptionHash<N> {
+        self.nodes.get(index)
+    }
+
+    /// same as `hash_of` but borrows the stored hash instead of copying it
+    pub fn hash_ref(&self, absolute_index: u32) -> Option<&Hash<N>> {
+        self.nodes.get_ref(absolute_index as usize)
+    }
 
-        if index >= self.current_add_position || index < self.zero_index as usize || self.nodes[index].is_none() {
+    /// writes leaf `index` anywhere below capacity, replacing what's there. writing past the end makes
+    /// `size` `index + 1` and floats the root up to cover it; skipped leaves stay empty and, like any
+    /// absent node, fold as the empty subtree of their height. returns the replaced leaf. root history isn't recorded
+    pub fn set_leaf(&mut self, index: u32, value: Hash<N>) -> Result<OptionHash<N>> {
+        if index >= self.capacity() {
             bail!(MerkleTreeError::UpdateIndexError)
         }
+        self.flush_deferred();
 
-        let old_hash = self.nodes[index];
-        self.nodes[index] = Some(value);
+        let node = self.zero_index + index;
+        let old_hash = self.nodes.get(node as usize);
+        self.nodes.set(node as usize, value);
+        self.drop_metadata(index);
 
-        self.update_branch(index as u32);
+        if node as usize >= self.current_add_position {
+            self.invalidate_all_proofs();
+            self.current_add_position = node as usize + 1;
+            let old_root = self.root;
+            self.root = (1 << (self.depth() - self.current_depth())) - 1;
+            // a write far past the end can lift the root several levels above the old one,
+            // leaving the nodes between them to be calculated too
+            if old_root != self.root && self.nodes.is_set(old_root as usize) {
+                self.update_branch(old_root);
+            }
+        } else {
+            self.invalidate_leaf_proofs(index);
+        }
+
+        self.update_branch(node);
+        Ok(old_hash)
+    }
+
+    /// writes leaf `index` of a `new_sparse` tree. on an append tree it's `set_leaf`
+    pub fn set(&mut self, leaf_index: u32, value: Hash<N>) -> Result<()> {
+        self.set_leaf(leaf_index, value).map(|_| ())
+    }
+
+    /// appends `value` committed together with `metadata`: the stored leaf is `metadata_leaf(value, metadata)`
+    /// rather than `value`, and `leaf_metadata` returns `metadata` until the leaf is overwritten or removed
+    pub fn add_with_metadata(&mut self, value: Hash<N>, metadata: u64) -> Result<u32> {
+        let index = self.try_add(self.metadata_leaf(&value, metadata))?;
+        if self.metadata.len() <= index as usize {
+            self.metadata.resize(index as usize + 1, None);
+        }
+        self.metadata[index as usize] = Some(metadata);
+        Ok(index)
+    }
+
+    /// leaf committing to `value` and `metadata`: `generate_hash(value || metadata)`, metadata big endian.
+    /// verifiers recompute it to check a proof of such a leaf
+    pub fn metadata_leaf(&self, value: &Hash<N>, metadata: u64) -> Hash<N> {
+        let mut data = Vec::with_capacity(N + 8);
+        data.extend_from_slice(value);
+        data.extend_from_slice(&metadata.to_be_bytes());
+        self.hasher.generate_hash(&data)
+    }
+
+    /// metadata leaf `index` was added with by `add_with_metadata`
+    pub fn leaf_metadata(&self, index: u32) -> Option<u64> {
+        self.metadata.get(index as usize).copied().flatten()
+    }
+
+    fn drop_metadata(&mut self, index: u32) {
+        if let Some(metadata) = self.metadata.get_mut(index as usize) {
+            *metadata = None;
+        }
+    }
+
+    /// whether leaf `index` holds `value`. out of range indices are just `false`
+    pub fn contains_at(&self, index: u32, value: &Hash<N>) -> bool {
+        index < self.size() && self.nodes.get_ref((index + self.zero_index) as usize) == Some(value)
+    }
+
+    /// index of the first leaf equal to `leaf`, scanning every leaf (`O(n)`)
+    pub fn contains(&self, leaf: &Hash<N>) -> Option<u32> {
+        (self.zero_index as usize..self.current_add_position)
+            .position(|i| self.nodes.get_ref(i) == Some(leaf))
+            .map(|i| i as u32)
+    }
+
+    /// panicking `try_add`, for callers that size the tree up front
+    pub fn add(&mut self, value: Hash<N>) -> u32 {
+        match self.try_add(value) {
+            Ok(index) => index,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// appends `value` after the last leaf. returns its index, or `TreeFull` / `SlotOccupied`
+    pub fn try_add(&mut self, value: Hash<N>) -> Result<u32> {
+        if self.capacity() <= self.size() {
+            bail!(MerkleTreeError::TreeFull)
+        }
+
+        if self.nodes.is_set(self.current_add_position) {
+            bail!(MerkleTreeError::SlotOccupied)
+        }
 
         if log_enabled!(Level::Debug) {
-            debug!("Updating i[{}]. old: [{}]. new: [{}]",
-                   index, Self::to_hex(&old_hash.unwrap()[..3]), Self::to_hex(&self.nodes[index].unwrap()[..3]));
+            debug!("Adding {} to i[{}]", MerkleTree::to_hex(&value[..3]), self.current_add_position);
         }
 
-        Ok(old_hash.unwrap())
+        self.nodes.set(self.current_add_position, value);
+        self.invalidate_all_proofs();
+        self.current_add_position += 1;
+
+        self.root = (1 << (self.depth() - self.current_depth())) - 1;
+
+        let node = self.current_add_position as u32 - 1;
+        if self.deferring {
+            self.deferred_from.get_or_insert(node as usize);
+            self.current_root = self.nodes.get(self.root as usize);
+        } else {
+            self.update_branch(node);
+            self.record_root();
+        }
+        if let Some(observer) = &self.observer {
+            observer.on_add(node - self.zero_index);
+        }
+        Ok(node - self.zero_index)
     }
 
-    fn update_branch(&mut self, mut node: u32) {
-        while let Some(parent) = Self::parent(node) {
-            let siblings = Self::child_nodes(parent);
+    /// makes following `add` calls only write leaves. internal nodes (and so the root)
+    /// are left stale until `commit` calculates them in one bottom-up pass.
+    /// root history isn't recorded for the deferred appends
+    pub fn begin_defer(&mut self) {
+        self.deferring = true;
+    }
 
-            let left = self.nodes[siblings.0 as usize].unwrap_or(self.default_hash);
-            let right = self.nodes[siblings.1 as usize].unwrap_or(self.default_hash);
-            self.nodes[parent as usize] = Some(self.hasher.concat_hash(&left, &right));
-            node = parent;
+    /// calculates branches of every leaf added since `begin_defer` and returns to eager mode
+    pub fn commit(&mut self) {
+        self.flush_deferred();
+        self.deferring = false;
+    }
+
+    fn flush_deferred(&mut self) {
+        let (mut from, mut to) = match self.deferred_from.take() {
+            Some(from) => (from as u32, self.current_add_position as u32 - 1),
+            None => return,
+        };
+        // proofs cached while deferring saw stale internal nodes
+        self.invalidate_all_proofs();
 
-            if parent == self.root {
-                break;
+        while from != self.root {
+            from = MerkleTree::parent(from).unwrap();
+            to = MerkleTree::parent(to).unwrap();
+            for parent in from..=to {
+                let (left, right) = MerkleTree::child_nodes(parent);
+                let left = self.node_or_empty(left);
+                let right = self.node_or_empty(right);
+                self.nodes.set(parent as usize, self.hash_pair(&left, &right));
             }
         }
+
+        self.current_root = self.nodes.get(self.root as usize);
     }
 
-    pub fn tree_lvl(&self) -> u32 {
-        let nodes_size = self.nodes.len() as f64;
-        nodes_size.log2() as u32
+    /// root the tree had right after it reached `size` leaves, if still kept in the history
+    pub fn historical_root(&self, size: u32) -> OptionHash<N> {
+        self.root_history.iter()
+            .find(|(s, _)| *s == size)
+            .map(|(_, root)| *root)
     }
 
-    pub fn size(&self) -> u32 {
-        (self.current_add_position - self.zero_index as usize) as u32
+    fn record_root(&mut self) {
+        if self.root_history_limit == 0 {
+            return;
+        }
+
+        if self.root_history.len() == self.root_history_limit {
+            self.root_history.pop_front();
+        }
+
+        if let Some(root) = self.cached_root() {
+            self.root_history.push_back((self.size(), root));
+        }
     }
 
-    pub fn generate_hash(&self, data: &[u8]) -> Hash {
-        self.hasher.generate_hash(data)
+    /// appends `value` and returns its index together with the root right after the append.
+    /// panics where `add` does, see `try_add_commit`
+    pub fn add_commit(&mut self, value: Hash<N>) -> Appended<N> {
+        match self.try_add_commit(value) {
+            Ok(appended) => appended,
+            Err(e) => panic!("{}", e),
+        }
     }
-}
 
-impl MerkleTree {
-    fn left_child(of: u32) -> u32 {
-        2 * of + 1
+    /// `add_commit` failing as `try_add` does
+    pub fn try_add_commit(&mut self, value: Hash<N>) -> Result<Appended<N>> {
+        let index = self.try_add(value)?;
+        self.flush_deferred();
+        Ok(Appended { index, root: self.cached_root().unwrap() })
     }
 
-    fn right_child(of: u32) -> u32 {
-        2 * of + 2
+    /// same as `add` for callers holding a borrowed hash
+    pub fn add_ref(&mut self, value: &Hash<N>) -> u32 {
+        self.add(*value)
     }
 
-    fn child_nodes(parent: u32) -> (u32, u32) {
-        (Self::left_child(parent), Self::right_child(parent))
+    /// node occupancy bits, see `PackedNodes::occupancy_bitmap`
+    pub fn occupancy_bitmap(&self) -> &[u64] {
+        self.nodes.occupancy_bitmap()
     }
 
-    fn parent(of: u32) -> Option<u32> {
-        if of == 0 {
+    /// absolute (`hash_of`) index of the most recently added leaf
+    pub fn last_added_absolute(&self) -> Option<u32> {
+        if self.size() == 0 {
             None
         } else {
-            Some((of - 1) / 2)
+            Some(self.current_add_position as u32 - 1)
         }
     }
 
-    fn hash_as_hex_with_prefix(hash: &Hash) -> String {
-        let mut h = Self::hash_as_hex(hash);
-        h.insert_str(0, "0x");
-        h
+    pub fn update(&mut self, index: u32, value: Hash<N>) -> Result<Hash<N>> {
+        let index = match index.checked_add(self.zero_index) {
+            Some(node) if (node as usize) < self.current_add_position && self.nodes.is_set(node as usize) => node as usize,
+            _ => bail!(MerkleTreeError::UpdateIndexError),
+        };
+
+        let old_hash = self.nodes.get(index);
+        self.nodes.set(index, value);
+        self.drop_metadata(index as u32 - self.zero_index);
+        self.invalidate_leaf_proofs(index as u32 - self.zero_index);
+
+        self.update_branch(index as u32);
+        if let Some(observer) = &self.observer {
+            observer.on_update(index as u32 - self.zero_index);
+        }
+
+        if log_enabled!(Level::Debug) {
+            debug!("Updating i[{}]. old: [{}]. new: [{}]",
+                   index, MerkleTree::to_hex(&old_hash.unwrap()[..3]), MerkleTree::to_hex(&value[..3]));
+        }
+
+        Ok(old_hash.unwrap())
     }
 
-    fn hash_as_hex(hash: &Hash) -> String {
-        hex::encode(hash)
+    /// `update` of every `(index, value)` pair in order, hashing each touched internal node once.
+    /// all indices are checked first, so on error nothing is written
+    pub fn update_many(&mut self, updates: &[(u32, Hash<N>)]) -> Result<()> {
+        let mut dirty = Vec::with_capacity(updates.len());
+        for (index, _) in updates {
+            match index.checked_add(self.zero_index) {
+                Some(node) if (node as usize) < self.current_add_position && self.nodes.is_set(node as usize) => dirty.push(node),
+                _ => bail!(MerkleTreeError::UpdateIndexError),
+            }
+        }
+
+        for (node, (index, value)) in dirty.iter().zip(updates) {
+            self.nodes.set(*node as usize, *value);
+            self.drop_metadata(*index);
+            self.invalidate_leaf_proofs(*index);
+        }
+        if let Some(observer) = &self.observer {
+            updates.iter().for_each(|(index, _)| observer.on_update(*index));
+        }
+
+        dirty.sort_unstable();
+        dirty.dedup();
+        while dirty.first().is_some_and(|node| *node != self.root) {
+            for node in dirty.iter_mut() {
+                *node = MerkleTree::parent(*node).unwrap();
+            }
+            dirty.dedup();
+
+            for parent in &dirty {
+                let (left, right) = MerkleTree::child_nodes(*parent);
+                let left = self.node_or_empty(left);
+                let right = self.node_or_empty(right);
+                self.nodes.set(*parent as usize, self.hash_pair(&left, &right));
+            }
+        }
+
+        self.current_root = self.nodes.get(self.root as usize);
+        Ok(())
     }
 
-    fn to_hex(hash: &[u8]) -> String {
-        hex::encode(hash)
+    /// empties leaf `index` so it folds as the default hash again, returning the removed hash.
+    /// the slot stays counted in `size()`; internal nodes left without set children are emptied too
+    pub fn remove(&mut self, index: u32) -> Result<Hash<N>> {
+        let node = match index.checked_add(self.zero_index) {
+            Some(node) if (node as usize) < self.current_add_position => node,
+            _ => bail!(MerkleTreeError::UpdateIndexError),
+        };
+        let old_hash = self.nodes.get(node as usize).ok_or(MerkleTreeError::UpdateEmptyError)?;
+        self.flush_deferred();
+
+        self.nodes.clear(node as usize);
+        self.drop_metadata(index);
+        self.invalidate_leaf_proofs(index);
+        self.update_branch(node);
+        Ok(old_hash)
     }
-}
 
-impl Display for MerkleTree {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Length: {}, Capacity: {}, Root: {}, Size: {}, Next: {}",
-               self.nodes.len(), self.max_size, self.root, self.size(), self.current_add_position)
+    /// hashes `data` as a leaf and updates `index` with it. returns replaced leaf hash
+    pub fn update_data(&mut self, index: u32, data: &[u8]) -> Result<Hash<N>> {
+        let value = self.hasher.generate_hash(data);
+        self.update(index, value)
     }
-}
 
-impl Debug for MerkleTree {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut l = f.debug_list();
-        for n in self.nodes.iter() {
-            match n {
-                Some(r) => l.entry(&Self::to_hex(&r[..3])),
-                None => l.entry(&"None")
+    /// same as `update` but skips the branch recalculation when the leaf already holds `value`.
+    /// returns `None` for the unchanged case and the replaced hash otherwise
+    pub fn update_if_changed(&mut self, index: u32, value: Hash<N>) -> Result<OptionHash<N>> {
+        let absolute = index.checked_add(self.zero_index).ok_or(MerkleTreeError::UpdateIndexError)? as usize;
+
+        if absolute < self.current_add_position && self.nodes.get_ref(absolute) == Some(&value) {
+            return Ok(None);
+        }
+
+        self.update(index, value).map(Some)
+    }
+
+    /// same as `update` but returns `(absolute_index, new_hash)` of every rewritten node,
+    /// from the leaf up to the floating root, so a mirror can persist only those
+    pub fn update_with_changes(&mut self, index: u32, value: Hash<N>) -> Result<Vec<(u32, Hash<N>)>> {
+        self.update(index, value)?;
+
+        let mut node = index + self.zero_index;
+        let mut changes = Vec::with_capacity(self.current_depth() as usize + 1);
+        loop {
+            changes.push((node, self.nodes.get(node as usize).unwrap()));
+            if node == self.root {
+                return Ok(changes);
+            }
+            node = MerkleTree::parent(node).unwrap();
+        }
+    }
+
+    /// applies `update_with_changes` output of another tree holding the same leaves.
+    /// nothing is written unless the path recomputes here to exactly the given hashes
+    pub(crate) fn apply_changes(&mut self, changes: &[(u32, Hash<N>)]) -> Result<()> {
+        let (leaf, value) = match changes.first() {
+            Some(&(leaf, value)) if leaf >= self.zero_index && (leaf as usize) < self.current_add_position => (leaf, value),
+            _ => bail!(MerkleTreeError::DeltaError),
+        };
+
+        let mut node = leaf;
+        let mut hash = value;
+        for &(changed, changed_hash) in &changes[1..] {
+            let parent = match MerkleTree::parent(node) {
+                Some(parent) if node != self.root => parent,
+                _ => bail!(MerkleTreeError::DeltaError),
             };
+            let sibling = self.node_or_empty(MerkleTree::sibling(node));
+            let (left, right) = if node % 2 == 1 { (&hash, &sibling) } else { (&sibling, &hash) };
+            if changed != parent || !self.hasher.verify_pair(left, right, &changed_hash) {
+                bail!(MerkleTreeError::DeltaError)
+            }
+            hash = changed_hash;
+            node = parent;
         }
 
-        l.finish()
+        if node != self.root {
+            bail!(MerkleTreeError::DeltaError)
+        }
+        self.update(leaf - self.zero_index, value)?;
+        Ok(())
     }
-}
 
-#[derive(Error, Debug)]
-enum MerkleTreeError {
-    #[error("Wrong index")]
-    UpdateIndexError,
+    /// `concat_hash` of two children, reported to the observer
+    fn hash_pair(&self, left: &Hash<N>, right: &Hash<N>) -> Hash<N> {
+        if let Some(observer) = &self.observer {
+            observer.on_hash_call();
+        }
+        self.hasher.concat_hash(left, right)
+    }
 
-    #[error("Trying to update empty node")]
-    UpdateEmptyError,
+    /// hash `node` stands for: its own when set, otherwise the empty subtree of its height.
+    /// every fold, proof and audit reads absent nodes through here
+    fn node_or_empty(&self, node: u32) -> Hash<N> {
+        self.nodes.get(node as usize).unwrap_or(self.empty_subtrees[(self.depth() - MerkleTree::node_depth(node)) as usize])
+    }
 
-    #[error("Empty hash value not allowed")]
-    UpdateEmptyInputError,
-}
+    /// recalculates ancestors of `node` up to the floating root. `node` must be under the root
+    fn update_branch(&mut self, mut node: u32) {
+        while node != self.root {
+            let parent = MerkleTree::parent(node).unwrap();
+            let (left, right) = MerkleTree::child_nodes(parent);
 
-#[cfg(test)]
-mod tests {
-    use env_logger::{Builder, Env};
-    use libc::{c_char, c_void};
-    use rand::prelude::SliceRandom;
-    use rand::Rng;
-    use std::ptr::{null, null_mut};
+            if self.nodes.is_set(left as usize) || self.nodes.is_set(right as usize) {
+                let hash = self.hash_pair(&self.node_or_empty(left), &self.node_or_empty(right));
+                self.nodes.set(parent as usize, hash);
+            } else {
+                // only after `remove` emptied the last leaf below, the parent is an empty subtree again
+                self.nodes.clear(parent as usize);
+            }
+            node = parent;
+        }
 
-    use crate::ALLOC;
-    use crate::hash::ShaHasher;
+        self.current_root = self.nodes.get(self.root as usize);
+    }
 
-    use super::*;
+    /// root kept up to date by every mutation, so reading it never touches `nodes`
+    fn cached_root(&self) -> OptionHash<N> {
+        debug_assert_eq!(self.current_root, self.nodes.get(self.root as usize));
+        self.current_root
+    }
 
-    #[global_allocator]
-    pub static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+    /// levels count the tree was created with, leaves included
+    pub fn levels(&self) -> u32 {
+        self.levels
+    }
 
-    extern "C" fn write_cb(_: *mut c_void, message: *const c_char) {
-        print!("{}", String::from_utf8_lossy(unsafe {
-            std::ffi::CStr::from_ptr(message as *const i8).to_bytes()
-        }));
+    /// edges from node 0 down to a leaf in the full tree: `levels() - 1`
+    pub fn depth(&self) -> u32 {
+        self.levels - 1
     }
 
-    #[test]
-    fn merkle_tree() {
-        Builder::from_env(Env::default().default_filter_or("debug")).init();
+    /// edges from the floating root down to a leaf: the least `d` with `2^d >= size()`
+    pub fn current_depth(&self) -> u32 {
+        MerkleTree::depth_for(self.size())
+    }
+
+    pub fn size(&self) -> u32 {
+        (self.current_add_position - self.zero_index as usize) as u32
+    }
 
-        // memory_usage();
+    /// populated leaves in logical order, borrowed from the tree. gaps left by `set_leaf` are skipped
+    pub fn leaves(&self) -> impl Iterator<Item=&Hash<N>> + '_ {
+        (self.zero_index as usize..self.current_add_position).filter_map(move |i| self.nodes.get_ref(i))
+    }
 
-        let levels = 3;
-        let mut tree = MerkleTree::new(levels, ShaHasher::default());
-        info!("empty: {}", tree);
-        // memory_usage();
-        let hash_size = std::mem::size_of::<Hash>();
-        println!("Hash size: {}mb", hash_size * 1000_000 / 1024 / 1024);
+    /// leaf slots `leaves` walks over, same as `size()`
+    pub fn leaves_len(&self) -> u32 {
+        self.size()
+    }
 
-        unsafe {
-            let ptr: *const c_void = &tree as *const MerkleTree as *const c_void;
-            let size = jemalloc_sys::malloc_usable_size(ptr);
-            println!("Size is: {}", size)
-        }
+    /// whether `other` holds the same leaf (or gap) at every logical index, whatever hasher or levels
+    /// each tree uses. internal nodes and roots aren't looked at
+    pub fn compare_leaves(&self, other: &MerkleTree<N>) -> bool {
+        self.size() == other.size()
+            && (0..self.size() as usize).all(|i| {
+                self.nodes.get_ref(self.zero_index as usize + i) == other.nodes.get_ref(other.zero_index as usize + i)
+            })
+    }
 
-        let mut k = 0;
-        while k < 1 << levels - 1 {
-            let hash = tree.generate_hash("hello".as_bytes());
-            tree.add(hash);
-            info!("{:?}", tree);
+    /// writes the populated leaves and levels to `path`, internal nodes are left to `load_leaves` to recompute.
+    ///
+    /// Layout (big endian): `magic: "MTLF" | version: u8 | levels: u8 | hash_len: u8 | count: u32 |
+    /// (index: u32 | leaf: [u8; hash_len]) * count`, indices ascending
+    #[cfg(feature = "std")]
+    pub fn save_leaves(&self, path: &Path) -> Result<()> {
+        let leaves = self.leaves_map();
+        let mut bytes = Vec::with_capacity(LEAVES_HEADER_SIZE + leaves.len() * (4 + N));
+        bytes.extend_from_slice(LEAVES_MAGIC);
+        bytes.extend_from_slice(&[LEAVES_VERSION, self.levels as u8, N as u8]);
+        bytes.extend_from_slice(&(leaves.len() as u32).to_be_bytes());
+        for (index, leaf) in &leaves {
+            bytes.extend_from_slice(&index.to_be_bytes());
+            bytes.extend_from_slice(leaf);
+        }
 
-            let hash = tree.generate_hash("12345".as_bytes());
-            info!("update: {}", MerkleTree::to_hex(&hash[..3]));
+        std::fs::write(path, bytes).map_err(MerkleTreeError::from)?;
+        Ok(())
+    }
 
-            match tree.update(k, hash) {
-                Ok(t) => info!("Updated"),
-                Err(e) => panic!("Failed to update tree.\n {}", e),
-            }
+    /// rebuilds a tree saved by `save_leaves`, recomputing every internal node with `hasher`
+    #[cfg(feature = "std")]
+    pub fn load_leaves(path: &Path, hasher: impl Hasher<N> + 'static) -> Result<Self> {
+        let bytes = std::fs::read(path).map_err(MerkleTreeError::from)?;
+        if bytes.len() < LEAVES_HEADER_SIZE || &bytes[..4] != LEAVES_MAGIC || bytes[4] != LEAVES_VERSION || bytes[6] as usize != N {
+            bail!(MerkleTreeError::LeavesFormatError)
+        }
 
-            info!("{:?}\n", tree);
-            k += 1;
+        let levels = bytes[5] as u32;
+        let count = u32::from_be_bytes(bytes[7..11].try_into()?) as usize;
+        let body = &bytes[LEAVES_HEADER_SIZE..];
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) || body.len() != count * (4 + N) {
+            bail!(MerkleTreeError::LeavesFormatError)
         }
-        // memory_usage();
 
-        unsafe {
-            let ptr: *const c_void = &tree as *const MerkleTree as *const c_void;
-            let size = jemalloc_sys::malloc_usable_size(ptr);
-            println!("Size is: {}", size)
+        let mut tree = Self::new(levels, hasher);
+        let mut next = 0;
+        for record in body.chunks_exact(4 + N) {
+            let index = u32::from_be_bytes(record[..4].try_into()?);
+            if index < next || index >= tree.capacity() {
+                bail!(MerkleTreeError::LeavesFormatError)
+            }
+            tree.set_leaf(index, record[4..].try_into()?)?;
+            next = index + 1;
         }
 
-        info!("final: {}", tree);
+        Ok(tree)
     }
 
-    fn memory_usage() {
-        unsafe { jemalloc_sys::malloc_stats_print(Some(write_cb), null_mut(), null()) };
+    /// populated leaves keyed by logical index. internal nodes are left out as they can be recomputed
+    pub fn leaves_map(&self) -> BTreeMap<u32, Hash<N>> {
+        (self.zero_index as usize..self.current_add_position)
+            .filter_map(|i| self.nodes.get(i).map(|leaf| ((i - self.zero_index as usize) as u32, leaf)))
+            .collect()
+    }
+
+    /// siblings on the path from `absolute_node_index` up to the floating root, lowest first.
+    /// empty siblings are returned as the empty subtree hash of their height
+    pub fn subtree_proof(&self, absolute_node_index: u32) -> Result<Vec<Hash<N>>> {
+        let mut proof = Vec::new();
+        self.subtree_proof_into(absolute_node_index, &mut proof)?;
+        Ok(proof)
     }
 
-    pub trait HeapSizeOf {
-        fn size_of_including_self(&self) -> usize;
-        fn size_of_excluding_self(&self) -> usize;
+    /// inclusion proof of leaf `index`, carrying the leaf, its siblings up to the floating root and
+    /// the tree size. empty siblings are the empty subtree hash of their height, see `Proof`.
+    /// a gap (or any unset leaf of a `new_sparse` tree) is proven as holding the default hash,
+    /// unless `ZeroLeafPolicy::Occupancy` makes it `UpdateEmptyError`
+    pub fn proof(&self, index: u32) -> Result<Proof<N>> {
+        let mut siblings = Vec::new();
+        self.proof_into(index, &mut siblings)?;
+        Ok(Proof { leaf: self.node_or_empty(index + self.zero_index), index, siblings, size: self.size() })
     }
 
-    impl<T: HeapSizeOf> HeapSizeOf for Box<T> {
-        fn size_of_including_self(&self) -> usize {
-            unimplemented!()
+    /// siblings of `proof(index)` written into `buf`, cleared first, so servers can reuse one
+    /// allocation across many proofs. fails as `proof` does, leaving `buf` untouched
+    pub fn proof_into(&self, index: u32, buf: &mut Vec<Hash<N>>) -> Result<()> {
+        let node = self.proof_leaf_node(index)?;
+        if self.zero_leaf_policy == ZeroLeafPolicy::Occupancy && !self.nodes.is_set(node as usize) {
+            bail!(MerkleTreeError::UpdateEmptyError)
         }
+        self.path_siblings_into(node, buf);
+        Ok(())
+    }
+
+    /// `proof` of each of `indices` in order, built only as the iterator is advanced
+    pub fn iter_proofs_for<'a>(&'a self, indices: &'a [u32]) -> impl Iterator<Item=Result<Proof<N>>> + 'a {
+        indices.iter().map(move |index| self.proof(*index))
+    }
+
+    /// one proof of every leaf in `indexes` (any order, duplicates allowed), with each sibling shared
+    /// by several paths sent once. see `MultiProof`
+    pub fn multiproof(&self, indexes: &[u32]) -> Result<MultiProof<N>> {
+        if self.size() == 0 {
+            bail!(MerkleTreeError::EmptyTree)
+        }
+
+        let mut nodes = Vec::with_capacity(indexes.len());
+        for index in indexes {
+            match index.checked_add(self.zero_index) {
+                Some(node) if (node as usize) < self.current_add_position && self.nodes.is_set(node as usize) => nodes.push(node),
+                _ => bail!(MerkleTreeError::UpdateIndexError),
+            }
+        }
+        nodes.sort_unstable();
+        nodes.dedup();
+        if nodes.is_empty() {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+
+        let mut hashes = Vec::new();
+        while nodes[0] != self.root {
+            let mut i = 0;
+            while i < nodes.len() {
+                // a left node followed by its sibling climbs as a pair without proof hashes
+                if nodes[i] % 2 == 1 && nodes.get(i + 1) == Some(&(nodes[i] + 1)) {
+                    i += 2;
+                    continue;
+                }
+                hashes.push(self.node_or_empty(MerkleTree::sibling(nodes[i])));
+                i += 1;
+            }
+
+            for node in nodes.iter_mut() {
+                *node = MerkleTree::parent(*node).unwrap();
+            }
+            nodes.dedup();
+        }
+
+        if let Some(observer) = &self.observer {
+            indexes.iter().for_each(|index| observer.on_proof(*index));
+        }
+        Ok(MultiProof { hashes, size: self.size() })
+    }
+
+    /// proof that the tree of the first `old_size` leaves grew into this one by appends only,
+    /// checked by `verify_consistency`. holds the maximal complete subtrees of the old leaves, left
+    /// first (left out when `old_size` is a power of two: the old root is that subtree), then the right
+    /// siblings on the path from the last of them up to the current root. empty for `old_size` 0 or `size()`
+    pub fn consistency_proof(&self, old_size: u32) -> Result<Vec<Hash<N>>> {
+        if old_size > self.size() {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+        if old_size == 0 || old_size == self.size() {
+            return Ok(Vec::new());
+        }
+
+        let subtrees = consistency_subtrees(old_size);
+        let node_of = |(height, start): (u32, u32)| (1 << (self.depth() - height)) - 1 + (start >> height);
+        let mut proof: Vec<Hash<N>> = if subtrees.len() > 1 {
+            subtrees.iter().map(|s| self.node_or_empty(node_of(*s))).collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut node = node_of(*subtrees.last().unwrap());
+        while node != self.root {
+            if node % 2 == 1 {
+                proof.push(self.node_or_empty(MerkleTree::sibling(node)));
+            }
+            node = MerkleTree::parent(node).unwrap();
+        }
+
+        Ok(proof)
+    }
+
+    /// `proof(index)` telling for each sibling whether it's a stored node, for `verify_proof_detailed`
+    pub fn detailed_proof(&self, index: u32) -> Result<DetailedProof<N>> {
+        let proof = self.proof(index)?;
+        let mut node = index + self.zero_index;
+        let mut present = Vec::with_capacity(proof.siblings.len());
+        while node != self.root {
+            present.push(self.nodes.is_set(MerkleTree::sibling(node) as usize));
+            node = MerkleTree::parent(node).unwrap();
+        }
+        Ok(DetailedProof { proof, present })
+    }
+
+    /// `proof(index)` and `consistency_proof(old_size)` in one object, checked by `CombinedProof::verify`
+    pub fn combined_proof(&self, index: u32, old_size: u32) -> Result<CombinedProof<N>> {
+        let consistency = self.consistency_proof(old_size)?;
+        Ok(CombinedProof { membership: self.proof(index)?, old_size, consistency })
+    }
+
+    /// absolute node of leaf `index`. an empty tree has nothing to prove (`EmptyTree`), a single
+    /// leaf is its own root and gets no siblings
+    fn proof_leaf_node(&self, index: u32) -> Result<u32> {
+        if self.size() == 0 {
+            bail!(MerkleTreeError::EmptyTree)
+        }
+
+        let node = index.checked_add(self.zero_index).ok_or(MerkleTreeError::UpdateIndexError)?;
+        if node as usize >= self.current_add_position {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+        if let Some(observer) = &self.observer {
+            observer.on_proof(index);
+        }
+        Ok(node)
+    }
+
+    /// reuses one buffer for many leaf proofs, see `ProofBuilder`
+    pub fn proof_builder(&self) -> ProofBuilder<'_, N> {
+        ProofBuilder { tree: self, scratch: Vec::with_capacity(self.depth() as usize) }
+    }
+
+    fn subtree_proof_into(&self, absolute_node_index: u32, proof: &mut Vec<Hash<N>>) -> Result<()> {
+        if absolute_node_index as usize >= self.nodes.len() || !self.nodes.is_set(absolute_node_index as usize)
+            || MerkleTree::node_depth(absolute_node_index) < MerkleTree::node_depth(self.root) {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+
+        self.path_siblings_into(absolute_node_index, proof);
+        Ok(())
+    }
+
+    /// siblings from `node`, set or not, up to the floating root. `node` must be under the root
+    fn path_siblings_into(&self, mut node: u32, proof: &mut Vec<Hash<N>>) {
+        proof.clear();
+        proof.reserve((MerkleTree::node_depth(node) - MerkleTree::node_depth(self.root)) as usize);
+        while node != self.root {
+            proof.push(self.node_or_empty(MerkleTree::sibling(node)));
+            node = MerkleTree::parent(node).unwrap();
+        }
+    }
+
+    /// checks the tree holds exactly `expected` leaves in order and its root recomputes from them
+    pub fn verify_leaves(&self, expected: &[Hash<N>]) -> Result<bool> {
+        if self.size() as usize != expected.len() {
+            return Ok(false);
+        }
+
+        let leaves_match = (self.zero_index as usize..self.current_add_position)
+            .zip(expected)
+            .all(|(i, hash)| self.nodes.get_ref(i) == Some(hash));
+
+        Ok(leaves_match && self.recompute_root() == self.nodes.get(self.root as usize))
+    }
+
+    /// root value computed bottom-up from the leaves only, ignoring stored internal nodes
+    fn recompute_root(&self) -> OptionHash<N> {
+        let mut level: Vec<OptionHash<N>> = (self.zero_index as usize..self.nodes.len()).map(|i| self.nodes.get(i)).collect();
+        for height in 0..(self.depth() - MerkleTree::node_depth(self.root)) as usize {
+            let empty = self.empty_subtrees[height];
+            level = level.chunks(2)
+                .map(|pair| match pair {
+                    [None, None] => None,
+                    [l, r] => Some(self.hash_pair(&l.unwrap_or(empty), &r.unwrap_or(empty))),
+                    _ => unreachable!(),
+                })
+                .collect();
+        }
+
+        level[(self.root + 1 - (1 << MerkleTree::node_depth(self.root))) as usize]
+    }
+
+    /// empties the tree for reuse without freeing its nodes. levels, hasher, root history limit and
+    /// the `new_sparse` layout stay
+    pub fn clear(&mut self) {
+        self.nodes.clear_all();
+        self.invalidate_all_proofs();
+        self.reset_positions();
+        self.current_root = None;
+        self.deferred_from = None;
+        self.deferring = false;
+        self.root_history.clear();
+        self.metadata.clear();
+    }
+
+    /// checks the stored nodes against each other without panicking, for trees loaded from
+    /// untrusted storage. `O(n)` hashes, see `AuditReport`
+    pub fn audit(&self) -> AuditReport {
+        let leaves = self.zero_index as usize..self.nodes.len();
+        let occupancy_contiguous = leaves.clone().all(|i| self.nodes.is_set(i) == (i < self.current_add_position));
+        let size_within_capacity = self.size() <= self.capacity();
+        let root_index_valid = size_within_capacity && self.root == (1 << (self.depth() - self.current_depth())) - 1;
+
+        let mut inconsistent_nodes = Vec::new();
+        let root_depth = MerkleTree::node_depth(self.root);
+        for depth in root_depth..self.depth() {
+            let first = (1u32 << depth) - 1;
+            for node in first..first + (1 << (depth - root_depth)) {
+                let (left, right) = MerkleTree::child_nodes(node);
+                let expected = match (self.nodes.is_set(left as usize), self.nodes.is_set(right as usize)) {
+                    (false, false) => None,
+                    _ => Some(self.hasher.concat_hash(&self.node_or_empty(left), &self.node_or_empty(right))),
+                };
+                if self.nodes.get(node as usize) != expected {
+                    inconsistent_nodes.push(node);
+                }
+            }
+        }
+
+        AuditReport {
+            root_matches_recompute: self.recompute_root() == self.nodes.get(self.root as usize),
+            occupancy_contiguous,
+            size_within_capacity,
+            root_index_valid,
+            inconsistent_nodes,
+        }
+    }
+
+    /// rebuilds the tree into the fewest levels still holding all leaves. returns the new levels count
+    pub fn reduce_levels(&mut self) -> Result<u32> {
+        let levels = MerkleTree::levels_for(self.size());
+
+        if levels < self.levels {
+            self.rebuild(levels);
+        }
+
+        Ok(self.levels)
+    }
+
+    /// grows the tree, keeping its leaves, until `capacity() >= total_leaves`. no-op if it's already that big
+    pub fn reserve_for_total(&mut self, total_leaves: u32) -> Result<()> {
+        if total_leaves <= self.capacity() {
+            return Ok(());
+        }
+
+        let levels = MerkleTree::levels_for(total_leaves);
+        if levels > MerkleTree::MAX_LEVELS {
+            bail!(MerkleTreeError::CapacityError(total_leaves as usize))
+        }
+
+        self.rebuild(levels);
+        Ok(())
+    }
+
+    /// moves every leaf into a fresh `levels` layout. the size, including removed or never written
+    /// leaves at the end, and the `new_sparse` layout are kept
+    fn rebuild(&mut self, levels: u32) {
+        // `deferred_from` indexes the old layout
+        self.flush_deferred();
+        self.invalidate_all_proofs();
+        let size = self.size();
+        let leaves: Vec<OptionHash<N>> = (self.zero_index as usize..self.current_add_position)
+            .map(|i| self.nodes.get(i))
+            .collect();
+        let history = core::mem::take(&mut self.root_history);
+        // the leaves keep their metadata, `set_leaf` below would drop it
+        let metadata = core::mem::take(&mut self.metadata);
+
+        let nodes_size = (1 << levels) - 1;
+        let index = ((nodes_size - 1) / 2) as u32;
+
+        self.levels = levels;
+        self.zero_index = index;
+        self.nodes = PackedNodes::new(nodes_size);
+        self.reset_positions();
+        if !self.sparse && size > 0 {
+            self.current_add_position = (index + size) as usize;
+            self.root = (1 << (self.depth() - self.current_depth())) - 1;
+        }
+        self.empty_subtrees = Self::empty_subtrees(self.hasher.as_ref(), self.default_hash, levels);
+        self.current_root = None;
+
+        for (index, leaf) in leaves.into_iter().enumerate() {
+            if let Some(leaf) = leaf {
+                self.set_leaf(index as u32, leaf).unwrap();
+            }
+        }
+        self.root_history = history;
+        self.metadata = metadata;
+    }
+
+    pub fn generate_hash(&self, data: &[u8]) -> Hash<N> {
+        self.hasher.generate_hash(data)
+    }
+
+    /// `Hasher::algorithm_id` of the hasher that produced this tree's nodes
+    pub fn hasher_id(&self) -> &'static str {
+        self.hasher.algorithm_id()
+    }
+
+    /// hasher the tree was built with, so verifiers don't have to construct a matching one
+    pub fn hasher(&self) -> &dyn Hasher<N> {
+        self.hasher.as_ref()
+    }
+
+    /// hash applied to raw data to get a leaf value. verifiers must use it only at the leaf level
+    pub fn leaf_digest(&self, data: &[u8]) -> Hash<N> {
+        self.hasher.generate_hash(data)
+    }
+
+    /// hash applied to a pair of children to get their parent. verifiers must use it for every level above the leaves.
+    /// with a tagging hasher such as `ShaHasher`, `leaf_digest(l || r)` differs from `node_digest(l, r)`
+    pub fn node_digest(&self, left: &Hash<N>, right: &Hash<N>) -> Hash<N> {
+        self.hasher.concat_hash(left, right)
+    }
+
+    fn empty_subtrees(hasher: &dyn Hasher<N>, default_hash: Hash<N>, levels: u32) -> Vec<Hash<N>> {
+        let mut hashes = Vec::with_capacity(levels as usize);
+        hashes.push(default_hash);
+        for h in 1..levels as usize {
+            hashes.push(hasher.concat_hash(&hashes[h - 1], &hashes[h - 1]));
+        }
+        hashes
+    }
+}
+
+/// Constants shared by every `N`, and the features whose formats, errors or companion types
+/// carry a 32-byte `Hash`: checked building, non-membership, snapshots and frozen bytes
+impl MerkleTree {
+    pub const HASH_SIZE_BYTES: usize = 32;
+    /// not the 28 the old panic message advertised: every node is allocated up front, `2^levels - 1`
+    /// of them, so 27 levels of 32-byte hashes already take 4GiB and 28 would take 8GiB before the
+    /// first add. indices and `capacity_for` would fit a `u32` either way
+    pub const MAX_LEVELS: u32 = 27;
+
+    /// leaves a tree of `levels` levels holds: `2^(levels - 1)`
+    pub const fn capacity_for(levels: u32) -> u32 {
+        1 << (levels - 1)
+    }
+
+    /// builds a tree sized for `leaves` and checks its root equals `claimed_root`
+    pub fn from_leaves_checked(leaves: &[Hash], claimed_root: &Hash, hasher: impl Hasher + 'static) -> Result<Self> {
+        let levels = Self::levels_for(leaves.len() as u32);
+        if levels > Self::MAX_LEVELS {
+            bail!(MerkleTreeError::CapacityError(leaves.len()))
+        }
+
+        let mut tree = Self::new(levels, hasher);
+        for leaf in leaves {
+            tree.add(*leaf);
+        }
+
+        let computed = tree.cached_root().unwrap_or_else(|| tree.empty_root());
+        if &computed != claimed_root {
+            bail!(MerkleTreeError::RootMismatch { computed, claimed: *claimed_root })
+        }
+
+        Ok(tree)
+    }
+
+    /// proves `value` isn't a leaf by its neighbors. leaves must be sorted ascending (as byte strings),
+    /// only the two neighbors found by binary search are checked for it
+    pub fn non_membership_proof(&self, value: &Hash) -> Result<NonMembershipProof> {
+        let leaves = (self.zero_index as usize..self.current_add_position)
+            .map(|i| self.nodes.get_ref(i).ok_or(MerkleTreeError::UpdateEmptyError))
+            .collect::<core::result::Result<Vec<_>, _>>()?;
+        if leaves.is_empty() {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+        let upper = match leaves.binary_search(&value) {
+            Ok(_) => bail!(MerkleTreeError::PresentValue),
+            Err(upper) => upper,
+        };
+
+        let neighbor = |index: usize| -> Result<Neighbor> {
+            let node = self.zero_index + index as u32;
+            Ok(Neighbor { index: index as u32, leaf: *leaves[index], proof: self.subtree_proof(node)? })
+        };
+        let lower = if upper > 0 { Some(neighbor(upper - 1)?) } else { None };
+        let upper = if upper < leaves.len() { Some(neighbor(upper)?) } else { None };
+
+        Ok(NonMembershipProof { lower, upper })
+    }
+
+    /// immutable copy of the current tree. building it is `O(n)`, while updating a snapshot
+    /// shares every untouched node with the previous version. see `PersistentMerkleTree`
+    pub fn snapshot(&self) -> PersistentMerkleTree {
+        let start = self.zero_index as usize;
+        let leaves: Vec<OptionHash> = (start..start + (1 << self.current_depth())).map(|i| self.nodes.get(i)).collect();
+
+        PersistentMerkleTree::build(self.hasher.clone(), self.empty_subtrees.clone(), self.current_depth(), self.size(), &leaves)
+    }
+
+    /// consumes the tree into the minimal blob needed to serve it read-only. see `FrozenMerkleTree`
+    pub fn into_frozen_bytes(self) -> Vec<u8> {
+        let nodes: Vec<Hash> = (0..self.nodes.len() as u32).map(|node| self.node_or_empty(node)).collect();
+
+        FrozenMerkleTree::encode(self.levels, self.size(), self.root, self.hasher.algorithm_id(), nodes.iter())
+    }
+}
+
+// index math doesn't depend on `N`, generic code reaches it as `MerkleTree::..`
+impl MerkleTree {
+    /// depth of the floating root over `size` leaves: the least `d` with `2^d >= size`
+    pub(crate) fn depth_for(size: u32) -> u32 {
+        match size {
+            0 | 1 => 0,
+            size => 32 - (size - 1).leading_zeros(),
+        }
+    }
+
+    /// fewest levels whose capacity holds `leaves`
+    fn levels_for(leaves: u32) -> u32 {
+        let mut levels = 1;
+        while 1u64 << (levels - 1) < leaves as u64 {
+            levels += 1;
+        }
+        levels
+    }
+
+    /// edges from node 0 down to `node`
+    fn node_depth(node: u32) -> u32 {
+        31 - (node + 1).leading_zeros()
+    }
+
+    fn left_child(of: u32) -> u32 {
+        2 * of + 1
+    }
+
+    fn right_child(of: u32) -> u32 {
+        2 * of + 2
+    }
+
+    fn child_nodes(parent: u32) -> (u32, u32) {
+        (Self::left_child(parent), Self::right_child(parent))
+    }
+
+    pub(crate) fn sibling(of: u32) -> u32 {
+        if of % 2 == 1 { of + 1 } else { of - 1 }
+    }
+
+    pub(crate) fn parent(of: u32) -> Option<u32> {
+        if of == 0 {
+            None
+        } else {
+            Some((of - 1) / 2)
+        }
+    }
+
+    fn hash_as_hex_with_prefix(hash: &[u8]) -> String {
+        let mut h = Self::hash_as_hex(hash);
+        h.insert_str(0, "0x");
+        h
+    }
+
+    fn hash_as_hex(hash: &[u8]) -> String {
+        hex::encode(hash)
+    }
+
+    fn to_hex(hash: &[u8]) -> String {
+        hex::encode(hash)
+    }
+}
+
+/// Builds a `ShaHasher` tree with the fewest levels holding all collected leaves.
+///
+/// Panics if there are more leaves than a `MAX_LEVELS` tree holds.
+impl FromIterator<Hash> for MerkleTree {
+    fn from_iter<I: IntoIterator<Item=Hash>>(iter: I) -> Self {
+        let leaves: Vec<Hash> = iter.into_iter().collect();
+        let levels = Self::levels_for(leaves.len() as u32);
+        if leaves.len() > u32::MAX as usize || levels > Self::MAX_LEVELS {
+            panic!("Too many leaves to collect into a tree: {}", leaves.len());
+        }
+
+        let mut tree = MerkleTree::new(levels, ShaHasher::default());
+        for leaf in leaves {
+            tree.add(leaf);
+        }
+        tree
+    }
+}
+
+/// checks `node_hash` stored at `absolute_node_index` climbs to `root` with `proof` from `MerkleTree::subtree_proof`
+pub fn verify_subtree_proof<const N: usize>(node_hash: &Hash<N>, absolute_node_index: u32, proof: &[Hash<N>], root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+    let mut node = absolute_node_index;
+    let mut hash = *node_hash;
+    for sibling in proof {
+        hash = if node % 2 == 1 {
+            hasher.concat_hash(&hash, sibling)
+        } else {
+            hasher.concat_hash(sibling, &hash)
+        };
+        node = match MerkleTree::parent(node) {
+            Some(parent) => parent,
+            None => return false,
+        };
+    }
+
+    &hash == root
+}
+
+/// checks `leaf` at `index` folds to `root` along `proof`, a `Proof::path` from `MerkleTree::proof`.
+/// `size` is the leaf count the verifier was told `root` commits to (`Proof::size`): `index` must be
+/// below it and `proof` exactly as long as its depth. the side flags must agree with `index`, so a
+/// malformed proof is rejected instead of climbing wrong
+pub fn verify_proof<const N: usize>(leaf: &Hash<N>, index: u32, size: u32, proof: &[(Hash<N>, bool)], root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+    if index >= size || proof.len() != MerkleTree::depth_for(size) as usize {
+        return false;
+    }
+
+    let mut hash = *leaf;
+    for (level, (sibling, is_right)) in proof.iter().enumerate() {
+        if level >= 32 || (index >> level & 1 == 0) != *is_right {
+            return false;
+        }
+        hash = if *is_right {
+            hasher.concat_hash(&hash, sibling)
+        } else {
+            hasher.concat_hash(sibling, &hash)
+        };
+    }
+
+    // a [u64; 4] compare saves ~0.4ns of a ~11us level 20 verify, see mt_root_compare_benchmark
+    &hash == root
+}
+
+/// `verify_proof` for a leaf and siblings still in untrusted byte form, e.g. just decoded off the wire.
+/// errors with `MalformedProof` if any of them isn't exactly `N` bytes, before hashing anything
+pub fn verify_proof_slices<const N: usize>(leaf: &[u8], index: u32, size: u32, proof: &[(&[u8], bool)], root: &Hash<N>, hasher: &dyn Hasher<N>) -> Result<bool> {
+    let leaf: Hash<N> = leaf.try_into().map_err(|_| MerkleTreeError::MalformedProof)?;
+    let proof = proof.iter()
+        .map(|(sibling, is_right)| Ok(((*sibling).try_into().map_err(|_| MerkleTreeError::MalformedProof)?, *is_right)))
+        .collect::<Result<Vec<(Hash<N>, bool)>>>()?;
+
+    Ok(verify_proof(&leaf, index, size, &proof, root, hasher))
+}
+
+/// checks raw `leaf_bytes` is leaf `index` of a tree of `size` leaves committed to by `root`.
+///
+/// The leaf is encoded exactly as `MerkleTree::leaf_digest` does, `hasher.generate_hash(leaf_bytes)`
+/// with no prefix or length. `proof` is the leaf's `subtree_proof`: one sibling per level up to the
+/// floating root, so `ceil(log2(size))` of them, and bit `i` of `index` set means `proof[i]` is the
+/// left child. each step is `concat_hash(left, right)`
+pub fn verify_proof_encoded<const N: usize>(root: &Hash<N>, leaf_bytes: &[u8], index: u32, proof: &[Hash<N>], size: u32, hasher: &dyn Hasher<N>) -> bool {
+    verify_indexed_leaf(root, &hasher.generate_hash(leaf_bytes), index, proof, size, hasher)
+}
+
+pub(crate) fn verify_indexed_leaf<const N: usize>(root: &Hash<N>, leaf: &Hash<N>, index: u32, proof: &[Hash<N>], size: u32, hasher: &dyn Hasher<N>) -> bool {
+    if index >= size || proof.len() != MerkleTree::depth_for(size) as usize {
+        return false;
+    }
+
+    let mut hash = *leaf;
+    for (level, sibling) in proof.iter().enumerate() {
+        hash = if index >> level & 1 == 0 {
+            hasher.concat_hash(&hash, sibling)
+        } else {
+            hasher.concat_hash(sibling, &hash)
+        };
+    }
+
+    &hash == root
+}
+
+/// `Proof::verify` that also checks the presence flags: a sibling claimed empty must be the empty
+/// subtree hash of its level (from `generate_hash([0; N])`) and may not cover any leaf below `size`,
+/// so a prover can't fold a default hash in where a real node must be. errors with
+/// `SuspiciousDefaultSibling` on a bad flag and `MalformedProof` on a flag count differing from the
+/// siblings. assumes appended trees: in a tree with gaps (`set_leaf`, `remove`, `new_sparse`) empty
+/// siblings below `size` are legitimate, verify those with `Proof::verify`
+pub fn verify_proof_detailed<const N: usize>(detailed: &DetailedProof<N>, root: &Hash<N>, hasher: &dyn Hasher<N>) -> Result<bool> {
+    let proof = &detailed.proof;
+    if detailed.present.len() != proof.siblings.len() || proof.siblings.len() > 32 {
+        bail!(MerkleTreeError::MalformedProof)
+    }
+
+    let mut empty = hasher.generate_hash(&[0u8; N]);
+    for (level, (sibling, present)) in proof.siblings.iter().zip(&detailed.present).enumerate() {
+        let start = ((proof.index as u64 >> level) ^ 1) << level;
+        let populated = start < proof.size as u64;
+        if !present && (populated || sibling != &empty) {
+            bail!(MerkleTreeError::SuspiciousDefaultSibling { level })
+        }
+        empty = hasher.concat_hash(&empty, &empty);
+    }
+
+    Ok(proof.verify(root, hasher))
+}
+
+/// checks every `leaves[i]` is leaf `indexes[i]` of the tree committed to by `root`, with `proof`
+/// from `MerkleTree::multiproof` over the same indexes. each proof hash has to be used exactly once
+pub fn verify_multiproof<const N: usize>(leaves: &[Hash<N>], indexes: &[u32], proof: &MultiProof<N>, root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+    if leaves.is_empty() || leaves.len() != indexes.len() {
+        return false;
+    }
+
+    let mut level: Vec<(u32, Hash<N>)> = indexes.iter().copied().zip(leaves.iter().copied()).collect();
+    level.sort_unstable();
+    level.dedup();
+    if level.windows(2).any(|pair| pair[0].0 == pair[1].0) || level.last().unwrap().0 >= proof.size {
+        return false;
+    }
+
+    let mut hashes = proof.hashes.iter();
+    for _ in 0..MerkleTree::depth_for(proof.size) {
+        let mut parents = Vec::with_capacity(level.len());
+        let mut i = 0;
+        while i < level.len() {
+            let (position, hash) = level[i];
+            let parent = if position % 2 == 0 && level.get(i + 1).is_some_and(|next| next.0 == position + 1) {
+                i += 1;
+                hasher.concat_hash(&hash, &level[i].1)
+            } else {
+                let sibling = match hashes.next() {
+                    Some(sibling) => sibling,
+                    None => return false,
+                };
+                if position % 2 == 0 { hasher.concat_hash(&hash, sibling) } else { hasher.concat_hash(sibling, &hash) }
+            };
+            parents.push((position / 2, parent));
+            i += 1;
+        }
+        level = parents;
+    }
+
+    hashes.next().is_none() && level.len() == 1 && &level[0].1 == root
+}
+
+/// `(height, first leaf)` of the maximal complete subtrees covering leaves `0..size`, largest first
+fn consistency_subtrees(size: u32) -> Vec<(u32, u32)> {
+    let mut start = 0;
+    (0..32).rev()
+        .filter(|height| size >> height & 1 == 1)
+        .map(|height| {
+            let subtree = (height, start);
+            start += 1 << height;
+            subtree
+        })
+        .collect()
+}
+
+/// checks the tree of `new_size` leaves committed to by `new_root` starts with the `old_size` leaves
+/// committed to by `old_root`, with `proof` from `MerkleTree::consistency_proof`. any tree extends
+/// the empty one. absent leaves are taken as `generate_hash([0; N])`, not a `new_with_default` value
+pub fn verify_consistency<const N: usize>(old_root: &Hash<N>, old_size: u32, new_root: &Hash<N>, new_size: u32,
+                                          proof: &[Hash<N>], hasher: &dyn Hasher<N>) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == 0 {
+        return proof.is_empty();
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_root == new_root;
+    }
+
+    let subtrees = consistency_subtrees(old_size);
+    let (known, path) = if subtrees.len() > 1 {
+        if proof.len() < subtrees.len() {
+            return false;
+        }
+        proof.split_at(subtrees.len())
+    } else {
+        (core::slice::from_ref(old_root), proof)
+    };
+
+    let (lowest, start) = *subtrees.last().unwrap();
+    let mut empty = hasher.generate_hash(&[0u8; N]);
+    for _ in 0..lowest {
+        empty = hasher.concat_hash(&empty, &empty);
+    }
+
+    // old root: right of the old leaves every subtree is empty
+    let mut hash = known[known.len() - 1];
+    let mut left = known.len() - 1;
+    for height in lowest..MerkleTree::depth_for(old_size) {
+        hash = if start >> height & 1 == 1 {
+            left -= 1;
+            hasher.concat_hash(&known[left], &hash)
+        } else {
+            hasher.concat_hash(&hash, &empty)
+        };
+        empty = hasher.concat_hash(&empty, &empty);
+    }
+    if left != 0 || &hash != old_root {
+        return false;
+    }
+
+    // new root: same left subtrees, right siblings from the proof
+    let mut hash = known[known.len() - 1];
+    let mut left = known.len() - 1;
+    let mut path = path.iter();
+    for height in lowest..MerkleTree::depth_for(new_size) {
+        hash = if start >> height & 1 == 1 {
+            left -= 1;
+            hasher.concat_hash(&known[left], &hash)
+        } else {
+            match path.next() {
+                Some(sibling) => hasher.concat_hash(&hash, sibling),
+                None => return false,
+            }
+        };
+    }
+
+    path.next().is_none() && &hash == new_root
+}
+
+/// Present leaf next to an absent value, with its `subtree_proof`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Neighbor {
+    pub index: u32,
+    pub leaf: Hash,
+    pub proof: Vec<Hash>,
+}
+
+/// Adjacent leaves `lower < value < upper` of a tree with sorted leaves.
+/// `lower` is `None` when `value` is below the first leaf and `upper` when it's above the last
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NonMembershipProof {
+    pub lower: Option<Neighbor>,
+    pub upper: Option<Neighbor>,
+}
+
+/// checks `value` isn't a leaf of the sorted tree of `size` leaves committed to by `root`:
+/// both neighbors verify, are adjacent (or the first/last leaf) and strictly bracket `value`
+pub fn verify_non_membership(root: &Hash, value: &Hash, proof: &NonMembershipProof, size: u32, hasher: &dyn Hasher) -> bool {
+    let verifies = |n: &Neighbor| verify_indexed_leaf(root, &n.leaf, n.index, &n.proof, size, hasher);
+    match (&proof.lower, &proof.upper) {
+        (Some(lower), Some(upper)) => lower.index.checked_add(1) == Some(upper.index)
+            && &lower.leaf < value && value < &upper.leaf && verifies(lower) && verifies(upper),
+        (Some(lower), None) => lower.index.checked_add(1) == Some(size) && &lower.leaf < value && verifies(lower),
+        (None, Some(upper)) => upper.index == 0 && value < &upper.leaf && verifies(upper),
+        (None, None) => false,
+    }
+}
+
+/// Leaf proofs (`subtree_proof` of the leaf node) written into one reused buffer,
+/// for exports generating many proofs in a row
+pub struct ProofBuilder<'a, const N: usize = 32> {
+    tree: &'a MerkleTree<N>,
+    scratch: Vec<Hash<N>>,
+}
+
+impl<const N: usize> ProofBuilder<'_, N> {
+    /// siblings of leaf `index`, lowest first. the slice is overwritten by the next `build`
+    pub fn build(&mut self, index: u32) -> Result<&[Hash<N>]> {
+        let absolute = index.checked_add(self.tree.zero_index).ok_or(MerkleTreeError::UpdateIndexError)?;
+        self.tree.subtree_proof_into(absolute, &mut self.scratch)?;
+        if let Some(observer) = &self.tree.observer {
+            observer.on_proof(index);
+        }
+        Ok(&self.scratch)
+    }
+}
+
+/// Findings of `MerkleTree::audit`. a sound tree has every flag set and no inconsistent nodes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditReport {
+    /// stored root equals the one recomputed from the leaves alone
+    pub root_matches_recompute: bool,
+    /// every leaf slot below `size()` is set and none above it is
+    pub occupancy_contiguous: bool,
+    pub size_within_capacity: bool,
+    /// root sits where the floating root of `size()` leaves belongs
+    pub root_index_valid: bool,
+    /// internal nodes under the root not matching their children, by absolute index
+    pub inconsistent_nodes: Vec<u32>,
+}
+
+impl AuditReport {
+    pub fn is_sound(&self) -> bool {
+        self.root_matches_recompute && self.occupancy_contiguous && self.size_within_capacity
+            && self.root_index_valid && self.inconsistent_nodes.is_empty()
+    }
+}
+
+impl Display for AuditReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Root matches recompute: {}, Occupancy contiguous: {}, Size within capacity: {}, Root index valid: {}, Inconsistent nodes: {:?}",
+               self.root_matches_recompute, self.occupancy_contiguous, self.size_within_capacity, self.root_index_valid, self.inconsistent_nodes)
+    }
+}
+
+/// What `verify_subtree_proof_debug` found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyOutcome {
+    Valid,
+    /// hash recomputed with `proof[level]` differs from `expected[level]`
+    Mismatch { level: usize },
+    /// no intermediate told where the climb went wrong, only the root differs
+    RootMismatch,
+    /// proof climbs past the top of the tree
+    TooLong,
+}
+
+/// `verify_subtree_proof` telling where a failing proof went wrong.
+///
+/// `expected[i]` is the hash the climb should reach after combining with `proof[i]`,
+/// e.g. taken from a known good tree. it may be shorter than `proof` or empty,
+/// in which case only the root can be compared
+pub fn verify_subtree_proof_debug<const N: usize>(node_hash: &Hash<N>, absolute_node_index: u32, proof: &[Hash<N>], expected: &[Hash<N>],
+                                  root: &Hash<N>, hasher: &dyn Hasher<N>) -> VerifyOutcome {
+    let mut node = absolute_node_index;
+    let mut hash = *node_hash;
+    for (level, sibling) in proof.iter().enumerate() {
+        hash = if node % 2 == 1 {
+            hasher.concat_hash(&hash, sibling)
+        } else {
+            hasher.concat_hash(sibling, &hash)
+        };
+        if expected.get(level).is_some_and(|e| e != &hash) {
+            return VerifyOutcome::Mismatch { level };
+        }
+        node = match MerkleTree::parent(node) {
+            Some(parent) => parent,
+            None => return VerifyOutcome::TooLong,
+        };
+    }
+
+    if &hash == root { VerifyOutcome::Valid } else { VerifyOutcome::RootMismatch }
+}
+
+/// appends every leaf in one deferred pass, so filling an empty tree costs the same as `from_leaves`.
+/// panics like `add` when the leaves don't fit
+impl<const N: usize> Extend<Hash<N>> for MerkleTree<N> {
+    fn extend<I: IntoIterator<Item=Hash<N>>>(&mut self, iter: I) {
+        let deferring = self.deferring;
+        self.begin_defer();
+        for leaf in iter {
+            self.add(leaf);
+        }
+        if !deferring {
+            self.commit();
+        }
+    }
+}
+
+impl<const N: usize> Display for MerkleTree<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Length: {}, Capacity: {}, Root: {}, Size: {}, Next: {}, Hasher: {}",
+               self.nodes.len(), self.capacity(), self.root, self.size(), self.current_add_position, self.hasher_id())
+    }
+}
+
+/// every node by index, as in `hash_of`. `{:?}` shortens hashes to their first 3 bytes,
+/// `{:#?}` prints them in full, one node per line
+impl<const N: usize> Debug for MerkleTree<N> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let short = if f.alternate() { N } else { 3.min(N) };
+        let mut m = f.debug_map();
+        for (i, n) in self.nodes.iter().enumerate() {
+            match n {
+                Some(r) => m.entry(&i, &MerkleTree::to_hex(&r[..short])),
+                None => m.entry(&i, &"None")
+            };
+        }
+
+        m.finish()
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "std", derive(Error))]
+#[allow(clippy::enum_variant_names)]
+pub enum MerkleTreeError {
+    #[cfg_attr(feature = "std", error("Wrong index"))]
+    UpdateIndexError,
+
+    #[cfg_attr(feature = "std", error("Trying to update empty node"))]
+    UpdateEmptyError,
+
+    #[cfg_attr(feature = "std", error("Empty hash value not allowed"))]
+    UpdateEmptyInputError,
+
+    #[cfg_attr(feature = "std", error("Malformed frozen tree bytes"))]
+    FrozenFormatError,
+
+    #[cfg_attr(feature = "std", error("Not acceptable tree size {0}. Consider range [1-{}]", MerkleTree::MAX_LEVELS))]
+    LevelsError(u32),
+
+    #[cfg_attr(feature = "std", error("Not enough capacity for {0} leaves"))]
+    CapacityError(usize),
+
+    #[cfg_attr(feature = "std", error("Root mismatch. computed: {}, claimed: {}", hex::encode(.computed), hex::encode(.claimed)))]
+    RootMismatch { computed: Hash, claimed: Hash },
+
+    #[cfg_attr(feature = "std", error("Value is a leaf of the tree"))]
+    PresentValue,
+
+    #[cfg_attr(feature = "std", error("Delta doesn't apply to this tree"))]
+    DeltaError,
+
+    #[cfg_attr(feature = "std", error("Serialized tree state is inconsistent"))]
+    StateError,
+
+    #[cfg_attr(feature = "std", error("Malformed leaves file"))]
+    LeavesFormatError,
+
+    #[cfg_attr(feature = "std", error("Proof element has the wrong length"))]
+    MalformedProof,
+
+    #[cfg_attr(feature = "std", error("Malformed proof bytes"))]
+    ProofFormatError,
+
+    #[cfg_attr(feature = "std", error("Sibling at level {level} claimed empty where a node must be"))]
+    SuspiciousDefaultSibling { level: usize },
+
+    #[cfg_attr(feature = "std", error("Tree has no leaves to prove"))]
+    EmptyTree,
+
+    #[cfg_attr(feature = "std", error("We full"))]
+    TreeFull,
+
+    #[cfg_attr(feature = "std", error("Replacing not allowed for 'add' command"))]
+    SlotOccupied,
+
+    #[cfg_attr(feature = "std", error("Invalid hex hash"))]
+    FromHex(#[cfg_attr(feature = "std", from)] hex::FromHexError),
+
+    #[cfg(feature = "std")]
+    #[error("IO failure")]
+    Io(#[from] std::io::Error),
+}
+
+/// without `std` there's no thiserror, so errors display as their `Debug` form
+#[cfg(not(feature = "std"))]
+impl Display for MerkleTreeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl core::error::Error for MerkleTreeError {}
+
+#[cfg(not(feature = "std"))]
+impl From<hex::FromHexError> for MerkleTreeError {
+    fn from(err: hex::FromHexError) -> Self {
+        MerkleTreeError::FromHex(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use env_logger::{Builder, Env};
+    use rand::prelude::SliceRandom;
+    use rand::Rng;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::FrozenMerkleTree;
+    use crate::hash::{hasher_by_name, SaltedHasher, ShaHasher};
+
+    use super::*;
+
+    #[test]
+    fn merkle_tree() {
+        Builder::from_env(Env::default().default_filter_or("debug")).init();
+
+        let levels = 3;
+        let mut tree = MerkleTree::new(levels, ShaHasher::default());
+        info!("empty: {}", tree);
+        let hash_size = std::mem::size_of::<Hash>();
+        println!("Hash size: {}mb", hash_size * 1_000_000 / 1024 / 1024);
+
+        let mut k = 0;
+        while k < 1 << (levels - 1) {
+            let hash = tree.generate_hash("hello".as_bytes());
+            tree.add(hash);
+            info!("{:?}", tree);
+
+            let hash = tree.generate_hash("12345".as_bytes());
+            info!("update: {}", MerkleTree::to_hex(&hash[..3]));
+
+            match tree.update(k, hash) {
+                Ok(t) => info!("Updated"),
+                Err(e) => panic!("Failed to update tree.\n {}", e),
+            }
+
+            info!("{:?}\n", tree);
+            k += 1;
+        }
+
+        info!("final: {}", tree);
+    }
+
+    #[test]
+    fn levels_returns_declared_depth() {
+        for levels in 1..10 {
+            let tree = MerkleTree::new(levels, ShaHasher::default());
+            assert_eq!(tree.levels(), levels);
+            assert_eq!(tree.depth(), levels - 1);
+        }
+    }
+
+    #[test]
+    fn last_added_absolute_points_to_new_leaf() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.last_added_absolute(), None);
+
+        for i in 0..4u32 {
+            let hash = tree.generate_hash(&i.to_be_bytes());
+            let logical = tree.add(hash);
+            let absolute = tree.last_added_absolute().unwrap();
+
+            assert_eq!(absolute, logical + tree.zero_index);
+            assert_eq!(tree.hash_of(absolute as usize), Some(hash));
+        }
+    }
+
+    #[test]
+    fn root_history_keeps_last_roots() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default()).with_root_history(3);
+        let mut roots = vec![];
+        for i in 0..6u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+            roots.push(tree.hash_of(tree.root as usize).unwrap());
+        }
+
+        for size in 1..=3 {
+            assert_eq!(tree.historical_root(size), None);
+        }
+        for size in 4..=6 {
+            assert_eq!(tree.historical_root(size), Some(roots[size as usize - 1]));
+        }
+        assert_eq!(tree.historical_root(7), None);
+    }
+
+    #[test]
+    fn empty_root_is_default_leaf_hash() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.hash_of(tree.root as usize), None);
+        assert_eq!(tree.empty_root(), ShaHasher::default().generate_hash(&[0u8; MerkleTree::HASH_SIZE_BYTES]));
+
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        assert_ne!(tree.hash_of(tree.root as usize), Some(tree.empty_root()));
+    }
+
+    #[test]
+    fn reduce_levels_shrinks_to_minimal_depth() {
+        let mut tree = MerkleTree::new(20, ShaHasher::default());
+        let mut fresh = MerkleTree::new(8, ShaHasher::default());
+        let leaves: Vec<Hash> = (0..100u32).map(|i| tree.generate_hash(&i.to_be_bytes())).collect();
+        for leaf in &leaves {
+            tree.add(*leaf);
+            fresh.add(*leaf);
+        }
+        let root = tree.hash_of(tree.root as usize);
+
+        assert_eq!(tree.reduce_levels().unwrap(), 8);
+        assert_eq!(tree.levels(), 8);
+        assert_eq!(tree.size(), 100);
+        assert_eq!(tree.hash_of(tree.root as usize), root);
+        assert_eq!(tree.hash_of(tree.root as usize), fresh.hash_of(fresh.root as usize));
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(tree.hash_of(tree.zero_index as usize + i), Some(*leaf));
+        }
+
+        assert_eq!(tree.reduce_levels().unwrap(), 8);
+    }
+
+    #[test]
+    fn update_data_matches_update_with_hashed_value() {
+        let mut by_data = MerkleTree::new(3, ShaHasher::default());
+        let mut by_hash = MerkleTree::new(3, ShaHasher::default());
+        for i in 0..3u32 {
+            by_data.add(by_data.generate_hash(&i.to_be_bytes()));
+            by_hash.add(by_hash.generate_hash(&i.to_be_bytes()));
+        }
+
+        let old = by_data.update_data(1, "12345".as_bytes()).unwrap();
+        by_hash.update(1, by_hash.generate_hash("12345".as_bytes())).unwrap();
+
+        assert_eq!(old, by_data.generate_hash(&1u32.to_be_bytes()));
+        assert_eq!(by_data.hash_of(by_data.root as usize), by_hash.hash_of(by_hash.root as usize));
+        assert!(by_data.update_data(3, "12345".as_bytes()).is_err());
+        for err in [by_data.update_data(u32::MAX, "12345".as_bytes()).unwrap_err(), by_data.update(u32::MAX, [1u8; 32]).unwrap_err()] {
+            assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateIndexError)));
+        }
+    }
+
+    #[test]
+    fn from_hex_leaves_parses_and_reports_bad_hex() {
+        let leaf = MerkleTree::hash_as_hex_with_prefix(&[7u8; 32]);
+        let tree = MerkleTree::from_hex_leaves(3, ShaHasher::default(), &[&leaf, &leaf[2..]]).unwrap();
+        assert_eq!(tree.size(), 2);
+        assert_eq!(tree.hash_of(tree.zero_index as usize), Some([7u8; 32]));
+
+        let err = MerkleTree::from_hex_leaves(3, ShaHasher::default(), &["zz"]).err().unwrap();
+        match err.downcast_ref::<MerkleTreeError>() {
+            Some(e @ MerkleTreeError::FromHex(_)) => assert!(std::error::Error::source(e).is_some()),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hasher_recomputes_tree_nodes() {
+        let mut tree = MerkleTree::new(2, ShaHasher::default());
+        let left = tree.generate_hash("hello".as_bytes());
+        let right = tree.generate_hash("12345".as_bytes());
+        tree.add(left);
+        tree.add(right);
+
+        let hasher = tree.hasher();
+        assert_eq!(hasher.algorithm_id(), "sha3-256");
+        assert_eq!(hasher.generate_hash("hello".as_bytes()), left);
+        assert_eq!(Some(hasher.concat_hash(&left, &right)), tree.hash_of(tree.root as usize));
+    }
+
+    #[test]
+    fn verify_leaves_audits_leaves_and_root() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        let leaves: Vec<Hash> = (0..6u32).map(|i| tree.generate_hash(&i.to_be_bytes())).collect();
+        for leaf in &leaves {
+            tree.add(*leaf);
+        }
+
+        assert!(tree.verify_leaves(&leaves).unwrap());
+        assert!(!tree.verify_leaves(&leaves[..5]).unwrap());
+
+        let mut wrong = leaves.clone();
+        wrong[3] = tree.generate_hash("12345".as_bytes());
+        assert!(!tree.verify_leaves(&wrong).unwrap());
+
+        let root = tree.root as usize;
+        tree.nodes.set(root, [0u8; 32]);
+        assert!(!tree.verify_leaves(&leaves).unwrap());
+    }
+
+    #[test]
+    fn add_ref_matches_add() {
+        let mut owned = MerkleTree::new(3, ShaHasher::default());
+        let mut borrowed = MerkleTree::new(3, ShaHasher::default());
+        let hash = owned.generate_hash("hello".as_bytes());
+
+        assert_eq!(owned.add(hash), borrowed.add_ref(&hash));
+        assert_eq!(owned.hash_of(owned.zero_index as usize), borrowed.hash_of(borrowed.zero_index as usize));
+        assert_eq!(owned.hash_of(owned.root as usize), borrowed.hash_of(borrowed.root as usize));
+    }
+
+    #[test]
+    fn cached_root_follows_adds_and_updates() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        assert_eq!(tree.cached_root(), None);
+
+        for i in 0..8u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+            assert_eq!(tree.cached_root(), tree.recompute_root());
+
+            let index = i / 2;
+            tree.update_data(index, "12345".as_bytes()).unwrap();
+            assert_eq!(tree.cached_root(), tree.recompute_root());
+        }
+
+        tree.reduce_levels().unwrap();
+        assert_eq!(tree.cached_root(), tree.recompute_root());
+    }
+
+    #[test]
+    fn collect_sizes_tree_to_leaves() {
+        let hasher = ShaHasher::default();
+        let leaves: Vec<Hash> = (0..5u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+
+        let mut expected = MerkleTree::new(4, ShaHasher::default());
+        for leaf in &leaves {
+            expected.add(*leaf);
+        }
+
+        let tree: MerkleTree = leaves.into_iter().collect();
+        assert_eq!(tree.capacity(), 8);
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.cached_root(), expected.cached_root());
+    }
+
+    #[test]
+    fn from_leaves_checked_validates_claimed_root() {
+        let hasher = ShaHasher::default();
+        let leaves: Vec<Hash> = (0..3u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+        let root = leaves.iter().copied().collect::<MerkleTree>().cached_root().unwrap();
+
+        let tree = MerkleTree::from_leaves_checked(&leaves, &root, ShaHasher::default()).unwrap();
+        assert_eq!(tree.size(), 3);
+
+        let err = MerkleTree::from_leaves_checked(&leaves, &[0u8; 32], ShaHasher::default()).err().unwrap();
+        match err.downcast_ref::<MerkleTreeError>() {
+            Some(MerkleTreeError::RootMismatch { computed, claimed }) => {
+                assert_eq!(computed, &root);
+                assert_eq!(claimed, &[0u8; 32]);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn occupancy_bitmap_tracks_populated_nodes() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.occupancy_bitmap(), &[0]);
+
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        tree.add(tree.generate_hash("12345".as_bytes()));
+        // leaves 3, 4 and their parent 1
+        assert_eq!(tree.occupancy_bitmap(), &[0b11010]);
+    }
+
+    #[test]
+    fn depths_follow_fill_level() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        let expected: [(u32, u32); 9] = [(0, 0), (1, 0), (2, 1), (3, 2), (4, 2), (5, 3), (6, 3), (7, 3), (8, 3)];
+
+        for &(size, current_depth) in &expected {
+            if size > 0 {
+                tree.add(tree.generate_hash(&size.to_be_bytes()));
+            }
+
+            assert_eq!(tree.size(), size);
+            assert_eq!(tree.depth(), 3);
+            assert_eq!(tree.current_depth(), current_depth);
+            assert_eq!(MerkleTree::node_depth(tree.root), tree.depth() - current_depth);
+            assert_eq!(tree.cached_root(), tree.recompute_root());
+        }
+    }
+
+    #[test]
+    fn single_level_tree_holds_one_leaf() {
+        let mut tree = MerkleTree::new(1, ShaHasher::default());
+        let hash = tree.generate_hash("hello".as_bytes());
+        tree.add(hash);
+
+        assert_eq!(tree.current_depth(), 0);
+        assert_eq!(tree.cached_root(), Some(hash));
+    }
+
+    #[test]
+    fn leaves_map_round_trip() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..6u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        let map = tree.leaves_map();
+        assert_eq!(map.len(), 6);
+        assert_eq!(map[&0], tree.generate_hash(&0u32.to_be_bytes()));
+
+        let restored = MerkleTree::from_leaves_map(&map, 4, ShaHasher::default()).unwrap();
+        assert_eq!(restored.cached_root(), tree.cached_root());
+
+        let mut gapped = map.clone();
+        gapped.remove(&2);
+        assert!(MerkleTree::from_leaves_map(&gapped, 4, ShaHasher::default()).is_err());
+        assert!(MerkleTree::from_leaves_map(&map, 3, ShaHasher::default()).is_err());
+    }
+
+    #[test]
+    fn capacity_at_max_levels() {
+        assert_eq!(MerkleTree::capacity_for(MerkleTree::MAX_LEVELS), 1 << 26);
+
+        let mut tree = MerkleTree::new(MerkleTree::MAX_LEVELS, ShaHasher::default());
+        assert_eq!(tree.capacity(), 1 << (MerkleTree::MAX_LEVELS - 1));
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        assert_eq!(tree.size(), 1);
+        drop(tree);
+
+        // adding 2^26 leaves would touch 4GiB of nodes. a sparse tree starts out at full size
+        let mut tree = MerkleTree::new_sparse(MerkleTree::MAX_LEVELS, ShaHasher::default());
+        assert_eq!(tree.size(), tree.capacity());
+        tree.set_leaf(tree.capacity() - 1, tree.generate_hash("hello".as_bytes())).unwrap();
+        let err = tree.try_add([1u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+        assert!(tree.set_leaf(tree.capacity(), [1u8; 32]).is_err());
+
+        let levels = 16;
+        let leaves: Vec<Hash> = (0..MerkleTree::capacity_for(levels)).map(|i| tree.generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(levels, ShaHasher::default(), &leaves).unwrap();
+        assert_eq!(tree.size(), tree.capacity());
+        let err = tree.try_add([1u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+        assert!(MerkleTree::from_leaves(levels, ShaHasher::default(), &[&leaves[..], &[[1u8; 32]]].concat()).is_err());
+    }
+
+    #[test]
+    fn capacity_equals_addable_leaves() {
+        for levels in 1..8 {
+            let mut tree = MerkleTree::new(levels, ShaHasher::default());
+            for i in 0..tree.capacity() {
+                tree.add(tree.generate_hash(&i.to_be_bytes()));
+            }
+            assert_eq!(tree.size(), tree.capacity());
+            assert_eq!(tree.capacity(), MerkleTree::capacity_for(levels));
+            assert_eq!(tree.capacity() as usize, tree.nodes.len() - tree.zero_index as usize);
+            let err = tree.try_add([0u8; 32]).unwrap_err();
+            assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+        }
+    }
+
+    #[test]
+    fn subtree_proof_climbs_to_root() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..5u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+
+        // leaves, internal nodes on both sides and the root itself
+        for &node in &[7u32, 10, 11, 3, 4, 5, 1, 2, 0] {
+            let proof = tree.subtree_proof(node).unwrap();
+            assert_eq!(proof.len() as u32, MerkleTree::node_depth(node));
+            let hash = tree.hash_of(node as usize).unwrap();
+            assert!(verify_subtree_proof(&hash, node, &proof, &root, tree.hasher()));
+            assert!(!verify_subtree_proof(&[0u8; 32], node, &proof, &root, tree.hasher()));
+        }
+
+        // empty leaf and out of range node
+        assert!(tree.subtree_proof(12).is_err());
+        assert!(tree.subtree_proof(15).is_err());
+    }
+
+    #[test]
+    fn debug_verify_reports_corrupted_level() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..8u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+        let leaf = tree.hash_of(9).unwrap();
+        let expected: Vec<Hash> = [4usize, 1, 0].iter().map(|&n| tree.hash_of(n).unwrap()).collect();
+
+        let mut proof = tree.subtree_proof(9).unwrap();
+        assert_eq!(verify_subtree_proof_debug(&leaf, 9, &proof, &expected, &root, tree.hasher()), VerifyOutcome::Valid);
+
+        proof[1] = [0u8; 32];
+        assert_eq!(verify_subtree_proof_debug(&leaf, 9, &proof, &expected, &root, tree.hasher()),
+                   VerifyOutcome::Mismatch { level: 1 });
+        assert_eq!(verify_subtree_proof_debug(&leaf, 9, &proof, &[], &root, tree.hasher()), VerifyOutcome::RootMismatch);
+    }
+
+    #[test]
+    fn from_leaves_with_levels_leaves_room_to_add() {
+        let hasher = ShaHasher::default();
+        let leaves: Vec<Hash> = (0..3u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+
+        let mut tree = MerkleTree::from_leaves_with_levels(&leaves, 5, ShaHasher::default()).unwrap();
+        assert_eq!(tree.capacity(), 16);
+        assert_eq!(tree.size(), 3);
+
+        let root = tree.cached_root().unwrap();
+        for (i, leaf) in leaves.iter().enumerate() {
+            let node = tree.zero_index + i as u32;
+            let proof = tree.subtree_proof(node).unwrap();
+            assert!(verify_subtree_proof(leaf, node, &proof, &root, tree.hasher()));
+        }
+
+        tree.add(hasher.generate_hash("hello".as_bytes()));
+        assert_eq!(tree.size(), 4);
+
+        let err = MerkleTree::from_leaves_with_levels(&leaves, 2, ShaHasher::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::CapacityError(3))));
+        assert!(MerkleTree::from_leaves_with_levels(&leaves, 0, ShaHasher::default()).is_err());
+    }
+
+    #[test]
+    fn update_with_changes_lists_leaf_to_root_path() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..8u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        let new_leaf = tree.generate_hash("hello".as_bytes());
+        let changes = tree.update_with_changes(2, new_leaf).unwrap();
+
+        let path: Vec<u32> = changes.iter().map(|(node, _)| *node).collect();
+        assert_eq!(path, vec![9, 4, 1, 0]);
+        assert_eq!(changes[0].1, new_leaf);
+        for (node, hash) in &changes {
+            assert_eq!(tree.hash_of(*node as usize), Some(*hash));
+        }
+        assert!(tree.update_with_changes(8, new_leaf).is_err());
+    }
+
+    #[test]
+    fn contains_finds_first_index() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        let hello = tree.generate_hash("hello".as_bytes());
+        assert_eq!(tree.contains(&hello), None);
+
+        tree.add(tree.generate_hash("12345".as_bytes()));
+        tree.add(hello);
+        tree.add(hello);
+        tree.set_leaf(5, [1u8; 32]).unwrap();
+
+        assert_eq!(tree.contains(&hello), Some(1));
+        assert_eq!(tree.contains(&[1u8; 32]), Some(5));
+        assert_eq!(tree.contains(&[0u8; 32]), None);
+    }
+
+    #[test]
+    fn contains_at_checks_stored_leaf() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        let hello = tree.generate_hash("hello".as_bytes());
+        tree.add(hello);
+        tree.add(tree.generate_hash("12345".as_bytes()));
+
+        assert!(tree.contains_at(0, &hello));
+        assert!(!tree.contains_at(1, &hello));
+        assert!(!tree.contains_at(2, &hello));
+        assert!(!tree.contains_at(u32::MAX, &hello));
+    }
+
+    #[test]
+    fn display_names_hasher() {
+        let tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.hasher_id(), "sha3-256");
+        assert!(tree.to_string().contains("Hasher: sha3-256"));
+    }
+
+    #[test]
+    fn proof_builder_reuses_buffer() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..5u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+
+        let mut builder = tree.proof_builder();
+        for i in 0..5u32 {
+            let node = tree.zero_index + i;
+            let proof = builder.build(i).unwrap().to_vec();
+            assert_eq!(proof, tree.subtree_proof(node).unwrap());
+            assert!(verify_subtree_proof(&tree.hash_of(node as usize).unwrap(), node, &proof, &root, tree.hasher()));
+        }
+        assert!(builder.build(5).is_err());
+        assert!(builder.build(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn encoded_leaf_verifies_like_hashed_leaf() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..5u32 {
+            tree.add(tree.leaf_digest(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+
+        for i in 0..5u32 {
+            let node = tree.zero_index + i;
+            let proof = tree.subtree_proof(node).unwrap();
+            let leaf = i.to_be_bytes();
+            assert!(verify_subtree_proof(&tree.leaf_digest(&leaf), node, &proof, &root, tree.hasher()));
+            assert!(verify_proof_encoded(&root, &leaf, i, &proof, tree.size(), tree.hasher()));
+            assert!(!verify_proof_encoded(&root, "hello".as_bytes(), i, &proof, tree.size(), tree.hasher()));
+        }
+
+        let proof = tree.subtree_proof(tree.zero_index).unwrap();
+        assert!(!verify_proof_encoded(&root, &0u32.to_be_bytes(), 0, &proof, 16, tree.hasher()));
+        assert!(!verify_proof_encoded(&root, &0u32.to_be_bytes(), 5, &proof, 5, tree.hasher()));
+    }
+
+    #[test]
+    fn non_membership_of_bracketed_value() {
+        let hasher = ShaHasher::default();
+        let mut leaves: Vec<Hash> = (0..5u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+        leaves.sort();
+        let tree = MerkleTree::from_leaves_with_levels(&leaves, 4, ShaHasher::default()).unwrap();
+        let root = tree.cached_root().unwrap();
+
+        let mut between = leaves[2];
+        between[31] = between[31].wrapping_add(1);
+        assert!(leaves[2] < between && between < leaves[3]);
+
+        let proof = tree.non_membership_proof(&between).unwrap();
+        assert_eq!(proof.lower.as_ref().map(|n| n.index), Some(2));
+        assert_eq!(proof.upper.as_ref().map(|n| n.index), Some(3));
+        assert!(verify_non_membership(&root, &between, &proof, tree.size(), tree.hasher()));
+        assert!(!verify_non_membership(&root, &leaves[2], &proof, tree.size(), tree.hasher()));
+
+        let below = tree.non_membership_proof(&[0u8; 32]).unwrap();
+        assert!(below.lower.is_none());
+        assert!(verify_non_membership(&root, &[0u8; 32], &below, tree.size(), tree.hasher()));
+        let above = tree.non_membership_proof(&[0xff; 32]).unwrap();
+        assert!(above.upper.is_none());
+        assert!(verify_non_membership(&root, &[0xff; 32], &above, tree.size(), tree.hasher()));
+
+        let err = tree.non_membership_proof(&leaves[1]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::PresentValue)));
+    }
+
+    #[test]
+    fn proof_walks_to_floating_root() {
+        let mut tree = MerkleTree::new(5, ShaHasher::default());
+        for i in 0..3u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+
+        for i in 0..3u32 {
+            let proof = tree.proof(i).unwrap().path();
+            assert_eq!(proof.len() as u32, tree.current_depth());
+
+            let mut hash = tree.hash_of((tree.zero_index + i) as usize).unwrap();
+            for (sibling, is_right) in &proof {
+                hash = if *is_right { tree.node_digest(&hash, sibling) } else { tree.node_digest(sibling, &hash) };
+            }
+            assert_eq!(hash, root);
+        }
+
+        // leaf 2 has no right neighbor
+        assert_eq!(tree.proof(2).unwrap().path()[0], (tree.empty_root(), true));
+        assert!(!tree.proof(1).unwrap().path()[0].1);
+
+        let err = tree.proof(3).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateIndexError)));
+        assert!(tree.proof(u32::MAX).is_err());
+    }
+
+    // sha3-256 cut to 20 bytes, only to exercise a tree narrower than 32 bytes
+    struct Sha3_160;
+
+    impl Hasher<20> for Sha3_160 {
+        fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash<20> {
+            ShaHasher::default().concat_hash(left, right)[..20].try_into().unwrap()
+        }
+
+        fn generate_hash(&self, data: &[u8]) -> Hash<20> {
+            ShaHasher::default().generate_hash(data)[..20].try_into().unwrap()
+        }
+    }
+
+    #[test]
+    fn trees_of_other_hash_sizes() {
+        let mut narrow = MerkleTree::new(3, Sha3_160);
+        let leaves: Vec<Hash<20>> = (0..3u32).map(|i| narrow.generate_hash(&i.to_be_bytes())).collect();
+        for leaf in &leaves {
+            narrow.add(*leaf);
+        }
+        let root = narrow.root_hash().unwrap();
+        assert_eq!(root.len(), 20);
+        assert_eq!(root, narrow.node_digest(&narrow.node_digest(&leaves[0], &leaves[1]),
+                                            &narrow.node_digest(&leaves[2], &narrow.empty_root())));
+        for i in 0..3u32 {
+            assert!(verify_proof(&leaves[i as usize], i, 3, &narrow.proof(i).unwrap().path(), &root, narrow.hasher()));
+        }
+
+        let mut wide: MerkleTree<64> = MerkleTree::new(3, crate::hash::Sha3_512Hasher::default());
+        wide.add([7u8; 64]);
+        wide.add([8u8; 64]);
+        assert_eq!(wide.hasher_id(), "sha3-512");
+        assert_eq!(wide.root_hash(), Some(wide.node_digest(&[7u8; 64], &[8u8; 64])));
+
+        let tree: MerkleTree256 = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.empty_root().len(), MerkleTree::HASH_SIZE_BYTES);
+    }
+
+    #[test]
+    fn used_bytes_follow_fill_rate() {
+        let mut tree = MerkleTree::new(12, ShaHasher::default());
+        let empty = tree.used_bytes();
+        assert!(empty < tree.capacity_bytes() / 100);
+
+        for i in 0..tree.capacity() / 10 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        // a tenth of the leaves plus about as many internal nodes above them
+        let ratio = tree.used_bytes() as f64 / tree.capacity_bytes() as f64;
+        assert!(ratio > 0.09 && ratio < 0.12, "ratio {}", ratio);
+        assert_eq!(tree.capacity_bytes() - tree.used_bytes(), (tree.nodes.len() - tree.nodes.occupied()) * 32);
+    }
+
+    #[test]
+    fn leaves_returns_added_in_order() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        assert_eq!(tree.leaves().next(), None);
+
+        let added: Vec<Hash> = (0..5u32).map(|i| tree.generate_hash(&i.to_be_bytes())).collect();
+        for leaf in &added {
+            tree.add(*leaf);
+        }
+
+        assert!(tree.leaves().eq(added.iter()));
+        assert_eq!(tree.leaves().count() as u32, tree.leaves_len());
+    }
+
+    #[test]
+    fn save_and_load_leaves_round_trip() {
+        let path = std::env::temp_dir().join(format!("merkletree-leaves-{}.bin", std::process::id()));
+        let mut tree = MerkleTree::new(6, ShaHasher::default());
+        for i in 0..7u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        tree.set_leaf(20, tree.generate_hash("hello".as_bytes())).unwrap();
+
+        tree.save_leaves(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 11 + 8 * 36);
+
+        let loaded = MerkleTree::load_leaves(&path, ShaHasher::default()).unwrap();
+        assert_eq!(loaded.levels(), 6);
+        assert_eq!(loaded.size(), tree.size());
+        assert_eq!(loaded.root_hash(), tree.root_hash());
+
+        for bad in &[&bytes[..bytes.len() - 1], &bytes[..8], &[b"XTLF", &bytes[4..]].concat()[..]] {
+            std::fs::write(&path, bad).unwrap();
+            let err = MerkleTree::load_leaves(&path, ShaHasher::default()).unwrap_err();
+            assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::LeavesFormatError)));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(MerkleTree::load_leaves(&path, ShaHasher::default()).is_err());
+    }
+
+    #[test]
+    fn reserve_for_total_grows_and_keeps_leaves() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        for i in 0..3u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.root_hash();
+
+        tree.reserve_for_total(4).unwrap();
+        assert_eq!(tree.levels(), 3);
+
+        tree.reserve_for_total(9).unwrap();
+        assert_eq!(tree.levels(), 5);
+        assert!(tree.capacity() >= 9);
+        assert_eq!(tree.size(), 3);
+        assert_eq!(tree.root_hash(), root);
+        assert_eq!(tree.leaves().nth(2), Some(&tree.generate_hash(&2u32.to_be_bytes())));
+
+        let err = tree.reserve_for_total(u32::MAX).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::CapacityError(_))));
+        assert_eq!(tree.levels(), 5);
+    }
+
+    #[test]
+    fn rebuild_keeps_trailing_gaps_and_sparse_layout() {
+        let leaves: Vec<Hash> = (0..5u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        tree.remove(4).unwrap();
+        let root = tree.root_hash();
+
+        tree.reserve_for_total(40).unwrap();
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.root_hash(), root);
+        assert_eq!(tree.reduce_levels().unwrap(), 4);
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.root_hash(), root);
+        assert!(tree.proof(3).unwrap().verify(&root.unwrap(), tree.hasher()));
+
+        let mut tree = MerkleTree::new_sparse(3, ShaHasher::default());
+        let mut expected = MerkleTree::new_sparse(4, ShaHasher::default());
+        tree.set(1, leaves[1]).unwrap();
+        expected.set(1, leaves[1]).unwrap();
+
+        tree.reserve_for_total(8).unwrap();
+        assert_eq!(tree.size(), 8);
+        assert_eq!(tree.root_hash(), expected.root_hash());
+        assert!(tree.try_add(leaves[0]).is_err());
+    }
+
+    #[test]
+    fn rebuild_while_deferring_flushes_first() {
+        let leaves: Vec<Hash> = (0..7u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::new(10, ShaHasher::default());
+        tree.begin_defer();
+        for leaf in &leaves[..5] {
+            tree.add(*leaf);
+        }
+
+        assert_eq!(tree.reduce_levels().unwrap(), 4);
+        tree.add(leaves[5]);
+        tree.reserve_for_total(20).unwrap();
+        tree.add(leaves[6]);
+        tree.commit();
+
+        assert_eq!(tree.root_hash(), MerkleTree::from_leaves(6, ShaHasher::default(), &leaves).unwrap().root_hash());
+    }
+
+    #[test]
+    fn try_add_reports_full_tree() {
+        let mut tree = MerkleTree::new(2, ShaHasher::default());
+        assert_eq!(tree.try_add([1u8; 32]).unwrap(), 0);
+        assert_eq!(tree.try_add([2u8; 32]).unwrap(), 1);
+
+        let err = tree.try_add([3u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+        assert_eq!(tree.size(), 2);
+    }
+
+    #[test]
+    fn from_leaves_matches_repeated_add() {
+        let mut added = MerkleTree::new(6, ShaHasher::default());
+        let leaves: Vec<Hash> = (0..21u32).map(|i| added.generate_hash(&i.to_be_bytes())).collect();
+        for leaf in &leaves {
+            added.add(*leaf);
+        }
+
+        let built = MerkleTree::from_leaves(6, ShaHasher::default(), &leaves).unwrap();
+        assert_eq!(built.root_hash(), added.root_hash());
+        assert_eq!(built.size(), 21);
+        assert!(built.verify_leaves(&leaves).unwrap());
+
+        let err = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::CapacityError(21))));
+    }
+
+    #[test]
+    fn update_many_matches_sequential_updates() {
+        let leaves: Vec<Hash> = (0..11u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut batched = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap();
+        let mut sequential = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap();
+
+        let updates = [(7, [1u8; 32]), (0, [2u8; 32]), (8, [3u8; 32]), (7, [4u8; 32]), (10, [5u8; 32])];
+        for (index, value) in &updates {
+            sequential.update(*index, *value).unwrap();
+        }
+        batched.update_many(&updates).unwrap();
+        assert_eq!(batched.root_hash(), sequential.root_hash());
+        assert_eq!(batched.hash_of(batched.zero_index as usize + 7), Some([4u8; 32]));
+        assert_eq!(batched.recompute_root(), batched.root_hash());
+
+        let root = batched.root_hash();
+        assert!(batched.update_many(&[(1, [9u8; 32]), (11, [9u8; 32])]).is_err());
+        assert_eq!(batched.root_hash(), root);
+        assert_eq!(batched.leaves().nth(1), Some(&leaves[1]));
+        batched.update_many(&[]).unwrap();
+        assert_eq!(batched.root_hash(), root);
+    }
+
+    #[test]
+    fn compare_leaves_ignores_hasher() {
+        let leaves: Vec<Hash> = (0..5u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let mut migrated = MerkleTree::from_leaves(5, SaltedHasher::new(ShaHasher::default(), [7u8; 32]), &leaves).unwrap();
+
+        assert!(tree.compare_leaves(&migrated));
+        assert_ne!(tree.root_hash(), migrated.root_hash());
+
+        migrated.update(4, [0u8; 32]).unwrap();
+        assert!(!tree.compare_leaves(&migrated));
+        migrated.update(4, leaves[4]).unwrap();
+        migrated.set_leaf(6, leaves[0]).unwrap();
+        assert!(!tree.compare_leaves(&migrated));
+    }
+
+    #[test]
+    fn clear_resets_to_empty_tree() {
+        let leaves: Vec<Hash> = (0..6u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let root = tree.root_hash();
+        let nodes = tree.nodes.as_ptr();
+
+        tree.clear();
+        assert_eq!(tree.size(), 0);
+        assert_eq!(tree.root_hash(), None);
+        assert_eq!(tree.nodes.occupied(), 0);
+        assert_eq!(tree.nodes.as_ptr(), nodes);
+
+        for leaf in &leaves {
+            tree.add(*leaf);
+        }
+        assert_eq!(tree.root_hash(), root);
+    }
+
+    #[test]
+    fn clear_keeps_sparse_layout() {
+        let mut tree = MerkleTree::new_sparse(3, ShaHasher::default());
+        tree.set(2, tree.generate_hash("hello".as_bytes())).unwrap();
+
+        tree.clear();
+        assert_eq!(tree.size(), tree.capacity());
+        assert_eq!(tree.root_hash_or_empty(), MerkleTree::new_sparse(3, ShaHasher::default()).root_hash_or_empty());
+        let err = tree.try_add([1u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+
+        tree.set(2, tree.generate_hash("hello".as_bytes())).unwrap();
+        assert!(tree.proof(0).unwrap().verify(&tree.root_hash().unwrap(), tree.hasher()));
+    }
+
+    #[test]
+    fn proof_boundaries() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        let err = tree.proof(0).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::EmptyTree)));
+
+        let first = tree.generate_hash("hello".as_bytes());
+        tree.add(first);
+        assert!(tree.proof(0).unwrap().path().is_empty());
+        assert!(verify_proof(&first, 0, 1, &[], &tree.root_hash().unwrap(), tree.hasher()));
+        let err = tree.proof(1).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateIndexError)));
+
+        let second = tree.generate_hash("12345".as_bytes());
+        tree.add(second);
+        assert_eq!(tree.proof(0).unwrap().path(), vec![(second, true)]);
+        assert_eq!(tree.proof(1).unwrap().path(), vec![(first, false)]);
+    }
+
+    #[test]
+    fn new_boxed_takes_hasher_by_name() {
+        let mut boxed = MerkleTree::new_boxed(4, hasher_by_name("sha3-256").unwrap());
+        let mut plain = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..5u32 {
+            boxed.add(plain.generate_hash(&i.to_be_bytes()));
+            plain.add(plain.generate_hash(&i.to_be_bytes()));
+        }
+
+        assert_eq!(boxed.hasher_id(), "sha3-256");
+        assert_eq!(boxed.root_hash(), plain.root_hash());
+        assert!(hasher_by_name("md5").is_none());
+    }
+
+    #[test]
+    fn try_new_rejects_levels_out_of_range() {
+        for &levels in &[0, MerkleTree::MAX_LEVELS + 1, 30] {
+            let err = MerkleTree::try_new(levels, ShaHasher::default()).err().unwrap();
+            assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::LevelsError(l)) if *l == levels));
+        }
+        assert_eq!(MerkleTreeError::LevelsError(0).to_string(), "Not acceptable tree size 0. Consider range [1-27]");
+        assert_eq!(MerkleTree::try_new(1, ShaHasher::default()).unwrap().capacity(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Consider range [1-27]")]
+    fn new_panics_on_zero_levels() {
+        MerkleTree::new(0, ShaHasher::default());
+    }
+
+    #[test]
+    fn extend_matches_from_leaves() {
+        let leaves: Vec<Hash> = (0..16u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let built = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap();
+
+        let mut extended = MerkleTree::new(5, ShaHasher::default());
+        extended.extend(leaves.iter().copied());
+        assert_eq!(extended.root_hash(), built.root_hash());
+        assert!(!extended.deferring);
+
+        let mut halves = MerkleTree::new(5, ShaHasher::default());
+        halves.extend(leaves[..5].iter().copied());
+        halves.extend(leaves[5..].iter().copied());
+        assert_eq!(halves.root_hash(), built.root_hash());
+        assert_eq!(halves.recompute_root(), built.root_hash());
+    }
+
+    #[test]
+    fn multiproof_shares_overlapping_paths() {
+        let leaves: Vec<Hash> = (0..8u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap();
+        let root = tree.root_hash().unwrap();
+        let hasher = tree.hasher();
+
+        // 0 and 1 pair up, 5 needs 4, then 2 and 3 one level up: 3 hashes instead of 9
+        let proof = tree.multiproof(&[5, 0, 1]).unwrap();
+        assert_eq!(proof.hashes.len(), 3);
+        assert!(verify_multiproof(&[leaves[5], leaves[0], leaves[1]], &[5, 0, 1], &proof, &root, hasher));
+        assert!(verify_multiproof(&[leaves[0], leaves[1], leaves[5], leaves[0]], &[0, 1, 5, 0], &proof, &root, hasher));
+
+        assert!(!verify_multiproof(&[leaves[5], leaves[0], leaves[2]], &[5, 0, 1], &proof, &root, hasher));
+        assert!(!verify_multiproof(&[leaves[5], leaves[0]], &[5, 0], &proof, &root, hasher));
+        assert!(!verify_multiproof(&[leaves[0], leaves[1], leaves[5]], &[0, 1, 5], &MultiProof { size: 16, ..proof.clone() }, &root, hasher));
+        let mut longer = proof.clone();
+        longer.hashes.push(root);
+        assert!(!verify_multiproof(&[leaves[0], leaves[1], leaves[5]], &[0, 1, 5], &longer, &root, hasher));
+
+        let all: Vec<u32> = (0..8).collect();
+        assert!(tree.multiproof(&all).unwrap().hashes.is_empty());
+        assert!(verify_multiproof(&leaves, &all, &tree.multiproof(&all).unwrap(), &root, hasher));
+
+        let single = tree.multiproof(&[6]).unwrap();
+        assert_eq!(single.hashes, tree.proof(6).unwrap().siblings);
+
+        assert!(tree.multiproof(&[]).is_err());
+        assert!(tree.multiproof(&[8]).is_err());
+    }
+
+    #[test]
+    fn audit_reports_corrupted_node() {
+        let leaves: Vec<Hash> = (0..6u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let report = tree.audit();
+        assert!(report.is_sound(), "{}", report);
+
+        // parent of leaves 2 and 3
+        let node = MerkleTree::parent(tree.zero_index + 2).unwrap();
+        tree.nodes.set(node as usize, [0u8; 32]);
+        let report = tree.audit();
+        assert!(!report.is_sound());
+        assert_eq!(report.inconsistent_nodes, vec![MerkleTree::parent(node).unwrap(), node]);
+        assert!(report.root_matches_recompute);
+        assert!(report.occupancy_contiguous && report.root_index_valid);
+
+        tree.set_leaf(7, leaves[0]).unwrap();
+        assert!(!tree.audit().occupancy_contiguous);
+        assert!(tree.audit().root_index_valid);
+    }
+
+    #[test]
+    fn sparse_tree_proves_present_and_absent_leaves() {
+        let mut tree = MerkleTree::new_sparse(5, ShaHasher::default());
+        assert_eq!(tree.size(), 16);
+        assert_eq!(tree.root_hash_or_empty(), tree.empty_subtree_hashes()[4]);
+        assert!(tree.try_add([1u8; 32]).is_err());
+
+        let value = tree.generate_hash("hello".as_bytes());
+        tree.set(11, value).unwrap();
+        tree.set(2, value).unwrap();
+        assert!(tree.set(16, value).is_err());
+        assert_eq!(tree.root, 0);
+        assert_eq!(tree.size(), 16);
+        assert_eq!(tree.root_hash(), tree.recompute_root());
+
+        let root = tree.root_hash().unwrap();
+        let present = tree.proof(11).unwrap();
+        assert_eq!(present.leaf, value);
+        assert_eq!(present.siblings.len(), 4);
+        assert!(present.verify(&root, tree.hasher()));
+
+        let absent = tree.proof(3).unwrap();
+        assert_eq!(absent.leaf, tree.empty_root());
+        assert!(absent.verify(&root, tree.hasher()));
+        assert!(!Proof { leaf: value, ..absent }.verify(&root, tree.hasher()));
+
+        // leaf 7's sibling 6 is unset too, their parent is an empty subtree
+        let absent = tree.proof(7).unwrap();
+        assert_eq!(absent.siblings[0], tree.empty_root());
+        assert_eq!(absent.siblings[1], tree.empty_subtree_hashes()[1]);
+        assert!(absent.verify(&root, tree.hasher()));
+
+        let mut tree = MerkleTree::new_sparse(5, ShaHasher::default());
+        tree.set(0, value).unwrap();
+        let root = tree.root_hash().unwrap();
+        for i in 0..16 {
+            assert!(tree.proof(i).unwrap().verify(&root, tree.hasher()), "leaf {}", i);
+        }
+    }
+
+    #[test]
+    fn remove_folds_leaf_as_default() {
+        let leaves: Vec<Hash> = (0..5u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+
+        assert_eq!(tree.remove(1).unwrap(), leaves[1]);
+        assert_eq!(tree.hash_of((tree.zero_index + 1) as usize), None);
+        assert_eq!(tree.size(), 5);
+        let mut expected = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        expected.update(1, expected.empty_root()).unwrap();
+        assert_eq!(tree.root_hash(), expected.root_hash());
+
+        let err = tree.remove(1).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateEmptyError)));
+        assert!(tree.remove(5).is_err());
+
+        // leaf 4 is alone under its parent and grandparent, both go empty with it
+        tree.remove(4).unwrap();
+        assert!(tree.audit().inconsistent_nodes.is_empty());
+        for i in [0, 2, 3] {
+            tree.remove(i).unwrap();
+        }
+        assert_eq!(tree.root_hash(), None);
+        assert_eq!(tree.recompute_root(), None);
+
+        // both children of a parent removed: the parent is an empty subtree and both still prove
+        let leaves: Vec<Hash> = (0..8u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        tree.remove(2).unwrap();
+        tree.remove(3).unwrap();
+        let root = tree.root_hash().unwrap();
+        for i in [2, 3] {
+            let proof = tree.proof(i).unwrap();
+            assert_eq!(proof.leaf, tree.empty_root());
+            assert!(proof.verify(&root, tree.hasher()));
+        }
+        assert!(tree.proof(0).unwrap().verify(&root, tree.hasher()));
+        assert!(tree.audit().root_matches_recompute);
+        assert!(tree.audit().inconsistent_nodes.is_empty());
+    }
+
+    #[test]
+    fn zero_leaf_policy_proves_by_occupancy() {
+        let build = |policy| {
+            let mut tree = MerkleTree::new(4, ShaHasher::default()).with_zero_leaf_policy(policy);
+            let zero = tree.empty_root();
+            tree.add(zero);
+            tree.add([1u8; 32]);
+            tree.set_leaf(3, [2u8; 32]).unwrap();
+            tree
+        };
+
+        let tree = build(ZeroLeafPolicy::Occupancy);
+        let root = tree.root_hash().unwrap();
+        assert!(tree.is_member(0));
+        assert!(!tree.is_member(2));
+        assert!(tree.proof(0).unwrap().verify(&root, tree.hasher()));
+        let err = tree.proof(2).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateEmptyError)));
+
+        // by value the gap looks just like the zero valued member
+        let folding = build(ZeroLeafPolicy::FoldAsDefault);
+        assert_eq!(folding.root_hash(), tree.root_hash());
+        assert_eq!(folding.proof(2).unwrap().leaf, folding.proof(0).unwrap().leaf);
+    }
+
+    #[test]
+    fn consistency_proofs_between_sizes() {
+        let leaves: Vec<Hash> = (0..13u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let root_of = |size: usize| MerkleTree::from_leaves(5, ShaHasher::default(), &leaves[..size]).unwrap().root_hash_or_empty();
+        let hasher = ShaHasher::default();
+
+        for new_size in 1..=13u32 {
+            let tree = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves[..new_size as usize]).unwrap();
+            let new_root = tree.root_hash().unwrap();
+            for old_size in 0..=new_size {
+                let old_root = root_of(old_size as usize);
+                let proof = tree.consistency_proof(old_size).unwrap();
+                assert!(verify_consistency(&old_root, old_size, &new_root, new_size, &proof, &hasher), "{} -> {}", old_size, new_size);
+
+                if old_size > 0 && old_size < new_size {
+                    assert!(!verify_consistency(&[7u8; 32], old_size, &new_root, new_size, &proof, &hasher));
+                    assert!(!verify_consistency(&old_root, old_size, &new_root, new_size, &proof[1..], &hasher));
+                    let mut tampered = proof.clone();
+                    tampered[0] = [7u8; 32];
+                    assert!(!verify_consistency(&old_root, old_size, &new_root, new_size, &tampered, &hasher));
+                }
+            }
+            assert!(tree.consistency_proof(new_size + 1).is_err());
+        }
+
+        // 5 of 9 leaves: subtrees [0, 4) and [4, 5), then right siblings of [4, 5), [4, 6) and [0, 8)
+        let tree = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves[..9]).unwrap();
+        assert_eq!(tree.consistency_proof(5).unwrap().len(), 2 + 3);
+        assert_eq!(tree.consistency_proof(4).unwrap().len(), 2);
+        assert!(!verify_consistency(&root_of(4), 4, &root_of(3), 3, &[], &hasher));
+    }
+
+    #[test]
+    fn iter_proofs_for_keeps_requested_order() {
+        let leaves: Vec<Hash> = (0..6u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let root = tree.root_hash().unwrap();
+
+        let indices = [4, 0, 9, 2];
+        let proofs: Vec<Result<Proof>> = tree.iter_proofs_for(&indices).collect();
+        assert_eq!(proofs.len(), 4);
+        for (index, proof) in indices.iter().zip(&proofs) {
+            match proof {
+                Ok(proof) => {
+                    assert_eq!(proof.index, *index);
+                    assert_eq!(proof.leaf, leaves[*index as usize]);
+                    assert!(proof.verify(&root, tree.hasher()));
+                }
+                Err(_) => assert_eq!(*index, 9),
+            }
+        }
+        assert!(proofs[2].is_err());
+    }
+
+    #[test]
+    fn tree_is_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MerkleTree>();
+        assert_send_sync::<MerkleTree<64>>();
+        assert_send_sync::<PersistentMerkleTree>();
+
+        let leaves: Vec<Hash> = (0..8u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = Arc::new(MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap());
+        let root = tree.root_hash().unwrap();
+
+        let workers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let tree = tree.clone();
+                std::thread::spawn(move || (t..8).step_by(4).all(|i| tree.proof(i).unwrap().verify(&root, tree.hasher())))
+            })
+            .collect();
+        assert!(workers.into_iter().all(|worker| worker.join().unwrap()));
+    }
+
+    #[test]
+    fn metadata_is_committed_with_leaf() {
+        let value = ShaHasher::default().generate_hash("hello".as_bytes());
+        let build = |metadata| {
+            let mut tree = MerkleTree::new(3, ShaHasher::default());
+            tree.add([1u8; 32]);
+            assert_eq!(tree.add_with_metadata(value, metadata).unwrap(), 1);
+            tree
+        };
+
+        let mut first = build(1_700_000_000);
+        let second = build(1_700_000_001);
+        assert_ne!(first.hash_of((first.zero_index + 1) as usize), second.hash_of((second.zero_index + 1) as usize));
+        assert_ne!(first.root_hash(), second.root_hash());
+        assert_eq!(first.leaf_metadata(1), Some(1_700_000_000));
+        assert_eq!(first.leaf_metadata(0), None);
+        assert_eq!(first.leaf_metadata(5), None);
+
+        let proof = first.proof(1).unwrap();
+        assert_eq!(proof.leaf, first.metadata_leaf(&value, 1_700_000_000));
+        assert!(proof.verify(&first.root_hash().unwrap(), first.hasher()));
+
+        // resizing moves the leaves only, they still commit to the same metadata
+        first.reserve_for_total(100).unwrap();
+        assert_eq!(first.leaf_metadata(1), Some(1_700_000_000));
+        first.reduce_levels().unwrap();
+        assert_eq!(first.levels(), 2);
+        assert_eq!(first.leaf_metadata(1), Some(1_700_000_000));
+
+        first.update(1, value).unwrap();
+        assert_eq!(first.leaf_metadata(1), None);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn from_leaves_parallel_matches_serial() {
+        let hasher = ShaHasher::default();
+        for &size in &[0u32, 1, 5, 1000, 3000, 4096] {
+            let leaves: Vec<Hash> = (0..size).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+            let serial = MerkleTree::from_leaves(13, ShaHasher::default(), &leaves).unwrap();
+            let parallel = MerkleTree::from_leaves_parallel(13, ShaHasher::default(), &leaves).unwrap();
+
+            assert_eq!(parallel.root_hash(), serial.root_hash());
+            assert_eq!(parallel.size(), size);
+            assert!(parallel.audit().is_sound());
+        }
+
+        assert!(MerkleTree::from_leaves_parallel(2, ShaHasher::default(), &[[0u8; 32]; 3]).is_err());
+    }
+
+    #[test]
+    fn new_with_default_folds_absent_leaves_as_given_value() {
+        let hasher = ShaHasher::default();
+        let leaf = hasher.generate_hash("hello".as_bytes());
+        let mut tree = MerkleTree::new_with_default(3, ShaHasher::default(), [0u8; 32]);
+        for _ in 0..3 {
+            tree.add(leaf);
+        }
+
+        let expected = hasher.concat_hash(&hasher.concat_hash(&leaf, &leaf), &hasher.concat_hash(&leaf, &[0u8; 32]));
+        assert_eq!(tree.root_hash(), Some(expected));
+        assert_eq!(tree.empty_root(), [0u8; 32]);
+        assert!(tree.audit().is_sound());
+
+        let proof = tree.proof(2).unwrap();
+        assert_eq!(proof.path()[0], ([0u8; 32], true));
+        assert!(verify_proof(&leaf, 2, 3, &proof.path(), &expected, &hasher));
+
+        let default = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(default.empty_root(), hasher.generate_hash(&[0u8; 32]));
+    }
+
+    #[test]
+    fn root_hex_is_prefixed_root() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.root_hex(), None);
+
+        tree.add([0xabu8; 32]);
+        assert_eq!(tree.root_hex(), Some(format!("0x{}", "ab".repeat(32))));
+
+        tree.add([1u8; 32]);
+        assert_eq!(tree.root_hex(), Some(format!("0x{}", hex::encode(tree.root_hash().unwrap()))));
+    }
+
+    #[test]
+    fn debug_prints_indexed_nodes() {
+        let mut tree = MerkleTree::new(2, ShaHasher::default());
+        tree.add([0xabu8; 32]);
+
+        assert_eq!(format!("{:?}", tree), r#"{0: "None", 1: "ababab", 2: "None"}"#);
+        assert!(format!("{:#?}", tree).contains(&format!("1: \"{}\",\n", "ab".repeat(32))));
+    }
+
+    #[test]
+    fn sparse_set_leaf_floats_root() {
+        let mut tree = MerkleTree::new(5, ShaHasher::default());
+        let (leaf2, leaf5) = (tree.generate_hash("hello".as_bytes()), tree.generate_hash("12345".as_bytes()));
+
+        assert_eq!(tree.set_leaf(5, leaf5).unwrap(), None);
+        assert_eq!(tree.size(), 6);
+        assert_eq!(tree.current_depth(), 3);
+        assert_eq!(tree.set_leaf(2, leaf2).unwrap(), None);
+        assert_eq!(tree.size(), 6);
+
+        // leaves 0..8 under the root: only 2 and 5 present, absent nodes fold as the empty subtree of their height
+        let d = tree.empty_root();
+        let e1 = tree.empty_subtree_hashes()[1];
+        let n23 = tree.node_digest(&leaf2, &d);
+        let n45 = tree.node_digest(&d, &leaf5);
+        let root = tree.node_digest(&tree.node_digest(&e1, &n23), &tree.node_digest(&n45, &e1));
+        assert_eq!(tree.root_hash(), Some(root));
+        assert_eq!(tree.recompute_root(), Some(root));
+
+        assert_eq!(tree.leaves_map().keys().copied().collect::<Vec<_>>(), vec![2, 5]);
+        assert_eq!(tree.set_leaf(2, leaf5).unwrap(), Some(leaf2));
+        assert_eq!(tree.add(leaf2), 6);
+        assert!(tree.set_leaf(16, leaf2).is_err());
+
+        // root lifted from the single leaf over four levels at once
+        let mut tree = MerkleTree::new(5, ShaHasher::default());
+        tree.add(leaf2);
+        tree.set_leaf(9, leaf5).unwrap();
+        assert_eq!(tree.root, 0);
+        assert_eq!(tree.root_hash(), tree.recompute_root());
+
+        // every leaf of a gap folds as default and stays provable
+        let mut tree = MerkleTree::new(5, ShaHasher::default());
+        tree.add(leaf2);
+        tree.set_leaf(7, leaf5).unwrap();
+        let root = tree.root_hash().unwrap();
+        for i in 0..8 {
+            let proof = tree.proof(i).unwrap();
+            assert_eq!(proof.leaf == tree.empty_root(), (1..7).contains(&i));
+            assert!(proof.verify(&root, tree.hasher()), "leaf {}", i);
+        }
+    }
+
+    #[test]
+    fn root_hash_follows_floating_root() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        assert_eq!(tree.root_hash(), None);
+
+        for i in 0..5u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+            assert_eq!(tree.root_hash(), tree.hash_of(tree.root as usize));
+        }
+        assert_eq!(tree.root, 0);
+        assert_eq!(tree.root_hash(), tree.hash_of(0));
+    }
+
+    #[test]
+    fn root_hash_or_empty_is_total() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        assert_eq!(tree.root_hash_or_empty(), tree.empty_root());
+
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        tree.add(tree.generate_hash("12345".as_bytes()));
+        assert_eq!(tree.root_hash_or_empty(), tree.hash_of(tree.root as usize).unwrap());
+        assert_ne!(tree.root_hash_or_empty(), tree.empty_root());
+    }
+
+    #[test]
+    fn verify_proof_checks_every_leaf() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..8u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+
+        for i in 0..8u32 {
+            let leaf = tree.generate_hash(&i.to_be_bytes());
+            let proof = tree.proof(i).unwrap().path();
+            assert!(verify_proof(&leaf, i, 8, &proof, &root, tree.hasher()));
+            assert!(!verify_proof(&leaf, i ^ 1, 8, &proof, &root, tree.hasher()));
+            assert!(!verify_proof(&leaf, i, 8, &proof[1..], &root, tree.hasher()));
+            assert!(!verify_proof(&leaf, i, 16, &proof, &root, tree.hasher()));
+
+            let mut flipped = proof.clone();
+            flipped[0].1 = !flipped[0].1;
+            assert!(!verify_proof(&leaf, i, 8, &flipped, &root, tree.hasher()));
+        }
+        assert!(!verify_proof(&tree.empty_root(), 8, 8, &tree.proof(7).unwrap().path(), &root, tree.hasher()));
+    }
+
+    #[test]
+    fn detailed_proof_rejects_default_sibling_in_populated_region() {
+        let leaves: Vec<Hash> = (0..8u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves[..5]).unwrap();
+        let root = tree.root_hash().unwrap();
+
+        // leaf 4 of 5: level 0 and 1 siblings are absent, level 2 covers leaves 0..4
+        let detailed = tree.detailed_proof(4).unwrap();
+        assert_eq!(detailed.present, vec![false, false, true]);
+        assert!(verify_proof_detailed(&detailed, &root, tree.hasher()).unwrap());
+
+        let mut forged = detailed.clone();
+        forged.present[0] = true;
+        assert!(verify_proof_detailed(&forged, &root, tree.hasher()).unwrap());
+        forged.present.pop();
+        let err = verify_proof_detailed(&forged, &root, tree.hasher()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::MalformedProof)));
+
+        // full tree: a prover claiming leaf 3's sibling 2 was empty and folding the default in
+        let full = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let mut forged = full.detailed_proof(3).unwrap();
+        assert_eq!(forged.present, vec![true; 3]);
+        forged.present[0] = false;
+        forged.proof.siblings[0] = full.empty_root();
+        let err = verify_proof_detailed(&forged, &full.root_hash().unwrap(), full.hasher()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::SuspiciousDefaultSibling { level: 0 })));
+
+        // leaf 1 of 6: the level 2 sibling holds leaves 4 and 5 of its 4 slots
+        let partial = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves[..6]).unwrap();
+        let mut forged = partial.detailed_proof(1).unwrap();
+        assert!(verify_proof_detailed(&forged, &partial.root_hash().unwrap(), partial.hasher()).unwrap());
+        forged.present[2] = false;
+        forged.proof.siblings[2] = partial.empty_subtree_hashes()[2];
+        let err = verify_proof_detailed(&forged, &partial.root_hash().unwrap(), partial.hasher()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::SuspiciousDefaultSibling { level: 2 })));
+
+        // claimed empty but not the empty hash of its level
+        let mut forged = detailed;
+        forged.proof.siblings[1] = tree.empty_root();
+        let err = verify_proof_detailed(&forged, &root, tree.hasher()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::SuspiciousDefaultSibling { level: 1 })));
+    }
+
+    #[test]
+    fn combined_proof_parts_verify_independently() {
+        let leaves: Vec<Hash> = (0..11u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let old = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves[..6]).unwrap();
+        let tree = MerkleTree::from_leaves(5, ShaHasher::default(), &leaves).unwrap();
+        let (old_root, new_root) = (old.root_hash().unwrap(), tree.root_hash().unwrap());
+
+        let combined = tree.combined_proof(9, 6).unwrap();
+        assert!(combined.verify(&old_root, &new_root, tree.hasher()));
+        assert_eq!(combined.membership, tree.proof(9).unwrap());
+        assert!(combined.membership.verify(&new_root, tree.hasher()));
+        assert!(verify_consistency(&old_root, 6, &new_root, 11, &combined.consistency, tree.hasher()));
+
+        // an old root the tree didn't grow from fails the whole proof
+        assert!(!combined.verify(&new_root, &new_root, tree.hasher()));
+        assert!(tree.combined_proof(9, 12).is_err());
+        assert!(tree.combined_proof(11, 6).is_err());
+    }
+
+    #[test]
+    fn proof_into_reuses_buffer() {
+        let tree: MerkleTree = (0..11u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let root = tree.root_hash().unwrap();
+
+        let mut buf = Vec::new();
+        tree.proof_into(10, &mut buf).unwrap();
+        let allocation = buf.as_ptr();
+        for i in (0..11).rev() {
+            tree.proof_into(i, &mut buf).unwrap();
+            let proof = tree.proof(i).unwrap();
+            assert_eq!(buf, proof.siblings);
+            assert!(Proof { siblings: buf.clone(), ..proof }.verify(&root, tree.hasher()));
+            assert_eq!(buf.as_ptr(), allocation);
+        }
+
+        assert!(tree.proof_into(11, &mut buf).is_err());
+        assert_eq!(buf, tree.proof(0).unwrap().siblings);
+    }
+
+    #[test]
+    fn verify_proof_rejects_index_at_size() {
+        let leaves: Vec<Hash> = (0..5u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap();
+        let root = tree.root_hash().unwrap();
+
+        // siblings of the empty slot 5 under the same root: leaf 4 on its left, then leaf 4's upper path
+        let mut path = tree.proof(4).unwrap().path();
+        path[0] = (leaves[4], false);
+        assert!(verify_proof(&tree.empty_root(), 5, 6, &path, &root, tree.hasher()));
+        assert!(!verify_proof(&tree.empty_root(), 5, 5, &path, &root, tree.hasher()));
+    }
+
+    #[test]
+    fn verify_proof_slices_rejects_short_sibling() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        for i in 0..4u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        let root = tree.cached_root().unwrap();
+        let leaf = tree.generate_hash(&2u32.to_be_bytes());
+        let proof = tree.proof(2).unwrap().path();
+
+        let mut slices: Vec<(&[u8], bool)> = proof.iter().map(|(sibling, is_right)| (&sibling[..], *is_right)).collect();
+        assert!(verify_proof_slices(&leaf, 2, 4, &slices, &root, tree.hasher()).unwrap());
+
+        slices[1].0 = &proof[1].0[..31];
+        let err = verify_proof_slices(&leaf, 2, 4, &slices, &root, tree.hasher()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::MalformedProof)));
+        assert!(verify_proof_slices(&leaf[1..], 2, 4, &slices[..1], &root, tree.hasher()).is_err());
+    }
+
+    #[test]
+    fn subtree_proof_rejects_nodes_above_floating_root() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        tree.add(tree.generate_hash("12345".as_bytes()));
+
+        assert_eq!(tree.subtree_proof(7).unwrap().len(), 1);
+        assert!(tree.subtree_proof(1).is_err());
+    }
+
+    #[test]
+    fn empty_subtree_hashes_per_height() {
+        let mut tree = MerkleTree::new(5, ShaHasher::default());
+        let table = tree.empty_subtree_hashes().to_vec();
+
+        assert_eq!(table.len() as u32, tree.levels());
+        assert_eq!(table[0], tree.empty_root());
+        for h in 1..table.len() {
+            assert_eq!(table[h], tree.node_digest(&table[h - 1], &table[h - 1]));
+        }
+
+        tree.add(tree.generate_hash("hello".as_bytes()));
+        tree.reduce_levels().unwrap();
+        assert_eq!(tree.empty_subtree_hashes(), &table[..1]);
+    }
+
+    #[test]
+    fn add_commit_reports_new_root() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        let mut previous = None;
+        for i in 0..4u32 {
+            let appended = tree.add_commit(tree.generate_hash(&i.to_be_bytes()));
+
+            assert_eq!(appended.index, i);
+            assert_eq!(Some(appended.root), tree.cached_root());
+            assert_ne!(Some(appended.root), previous);
+            previous = Some(appended.root);
+        }
+
+        let mut tree = MerkleTree::new(1, ShaHasher::default());
+        assert_eq!(tree.try_add_commit([1u8; 32]).unwrap().index, 0);
+        let err = tree.try_add_commit([2u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+    }
+
+    #[test]
+    fn deferred_adds_match_eager_after_commit() {
+        let mut eager = MerkleTree::new(5, ShaHasher::default());
+        let mut deferred = MerkleTree::new(5, ShaHasher::default());
+        for i in 0..3u32 {
+            eager.add(eager.generate_hash(&i.to_be_bytes()));
+            deferred.add(deferred.generate_hash(&i.to_be_bytes()));
+        }
+
+        deferred.begin_defer();
+        for i in 3..13u32 {
+            eager.add(eager.generate_hash(&i.to_be_bytes()));
+            deferred.add(deferred.generate_hash(&i.to_be_bytes()));
+        }
+        assert_ne!(deferred.cached_root(), eager.cached_root());
+
+        deferred.commit();
+        assert_eq!(deferred.cached_root(), eager.cached_root());
+        assert_eq!(deferred.recompute_root(), eager.cached_root());
+
+        deferred.add(deferred.generate_hash("hello".as_bytes()));
+        eager.add(eager.generate_hash("hello".as_bytes()));
+        assert_eq!(deferred.cached_root(), eager.cached_root());
+    }
+
+    #[test]
+    fn hash_ref_borrows_stored_value() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        let hash = tree.generate_hash("hello".as_bytes());
+        let index = tree.add(hash) + tree.zero_index;
+
+        assert_eq!(tree.hash_ref(index), Some(&hash));
+        assert_eq!(tree.hash_ref(index).copied(), tree.hash_of(index as usize));
+        assert_eq!(tree.hash_ref(index + 1), None);
+    }
+
+    struct CountingHasher {
+        inner: ShaHasher,
+        concat_calls: Arc<AtomicUsize>,
+    }
+
+    impl CountingHasher {
+        fn new() -> (Self, Arc<AtomicUsize>) {
+            let counter = Arc::new(AtomicUsize::new(0));
+            (CountingHasher { inner: ShaHasher::default(), concat_calls: counter.clone() }, counter)
+        }
+    }
+
+    impl Hasher for CountingHasher {
+        fn concat_hash(&self, left: &[u8], right: &[u8]) -> Hash {
+            self.concat_calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.concat_hash(left, right)
+        }
+
+        fn generate_hash(&self, data: &[u8]) -> Hash {
+            self.inner.generate_hash(data)
+        }
+    }
+
+    #[test]
+    fn update_if_changed_skips_unchanged_leaf() {
+        let (hasher, concat_calls) = CountingHasher::new();
+        let mut tree = MerkleTree::new(4, hasher);
+        let hash = tree.generate_hash("hello".as_bytes());
+        tree.add(hash);
+        tree.add(hash);
+
+        concat_calls.store(0, Ordering::SeqCst);
+        assert_eq!(tree.update_if_changed(1, hash).unwrap(), None);
+        assert_eq!(concat_calls.load(Ordering::SeqCst), 0);
+
+        let other = tree.generate_hash("12345".as_bytes());
+        assert_eq!(tree.update_if_changed(1, other).unwrap(), Some(hash));
+        assert!(concat_calls.load(Ordering::SeqCst) > 0);
+
+        assert!(tree.update_if_changed(5, hash).is_err());
+        let err = tree.update_if_changed(u32::MAX, hash).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::UpdateIndexError)));
+    }
+
+    #[test]
+    fn frozen_bytes_round_trip() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..5u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        let expected: Vec<Hash> = (0..15).map(|i| tree.node_or_empty(i)).collect();
+        let root = tree.hash_of(tree.root as usize).unwrap();
+
+        let proofs: Vec<Proof> = (0..5).map(|i| tree.proof(i).unwrap()).collect();
+        let hasher = ShaHasher::default();
+
+        let frozen = FrozenMerkleTree::from_bytes(&tree.into_frozen_bytes()).unwrap();
+
+        for (i, proof) in proofs.iter().enumerate() {
+            let frozen_proof = frozen.proof(i as u32).unwrap();
+            assert_eq!(&frozen_proof, proof);
+            assert!(frozen_proof.verify(&root, &hasher));
+        }
+        assert!(frozen.proof(5).is_err());
+
+        assert_eq!(frozen.size(), 5);
+        assert_eq!(frozen.levels(), 4);
+        assert_eq!(frozen.algorithm_id(), "sha3-256");
+        assert_eq!(frozen.root_hash(), root);
+        for (i, hash) in expected.iter().enumerate() {
+            assert_eq!(&frozen.hash_of(i), hash);
+        }
+    }
+
+    #[test]
+    fn leaf_and_node_digests_are_applied_per_level() {
+        let mut tree = MerkleTree::new(2, ShaHasher::default());
+        let data: Vec<Hash> = vec![[1u8; 32], [2u8; 32]];
+        for d in &data {
+            tree.add(tree.leaf_digest(d));
+        }
+        let root = tree.hash_of(tree.root as usize).unwrap();
+
+        let left = tree.leaf_digest(&data[0]);
+        let right = tree.leaf_digest(&data[1]);
+        assert_eq!(tree.node_digest(&left, &right), root);
+
+        // verifier mistakenly hashing the leaf with the internal node function
+        let wrong_left = tree.node_digest(&data[0], &data[0]);
+        assert_ne!(tree.node_digest(&wrong_left, &right), root);
+
+        // verifier skipping the leaf hash entirely
+        assert_ne!(tree.node_digest(&data[0], &data[1]), root);
+    }
+}
+
+/// jemalloc memory inspection, `cargo test --features jemalloc-bench` on platforms jemalloc builds for
+#[cfg(all(test, feature = "jemalloc-bench"))]
+mod memory_tests {
+    use libc::{c_char, c_void};
+    use std::ptr::{null, null_mut};
+
+    use crate::hash::ShaHasher;
+
+    use super::*;
+
+    #[global_allocator]
+    pub static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+    extern "C" fn write_cb(_: *mut c_void, message: *const c_char) {
+        print!("{}", String::from_utf8_lossy(unsafe {
+            std::ffi::CStr::from_ptr(message).to_bytes()
+        }));
+    }
+
+    fn memory_usage() {
+        unsafe { jemalloc_sys::malloc_stats_print(Some(write_cb), null_mut(), null()) };
+    }
+
+    fn nodes_usable_size(tree: &MerkleTree) -> usize {
+        let ptr: *const c_void = tree.nodes.as_ptr() as *const c_void;
+        unsafe { jemalloc_sys::malloc_usable_size(ptr) }
+    }
+
+    #[test]
+    fn nodes_allocation_size() {
+        let levels = 3;
+        let mut tree = MerkleTree::new(levels, ShaHasher::default());
+        println!("Size is: {}", nodes_usable_size(&tree));
 
-        fn size_of_excluding_self(&self) -> usize {
-            // heap_size_of(&**self as *const T as *const c_void) + (**self).size_of_excluding_self()
-            0
+        for _ in 0..tree.capacity() {
+            tree.add(tree.generate_hash("hello".as_bytes()));
         }
+        println!("Size is: {}", nodes_usable_size(&tree));
+        memory_usage();
     }
 }
@@ -0,0 +1,156 @@
+use alloc::collections::BTreeMap;
+use anyhow::Result;
+
+use crate::proof::Proof;
+
+use super::MerkleTree;
+
+/// Proofs handed out by `cached_proof`, see `with_proof_cache`.
+///
+/// Two leaves' paths always meet below the root, so a write to leaf `i` reaches a sibling of every
+/// other cached proof and only the proof of `i` itself keeps its siblings. adds, size changes and
+/// `commit` drop them all: `size` and possibly the length of every proof change with them.
+impl<const N: usize> MerkleTree<N> {
+    /// keeps the proofs `cached_proof` builds until a write makes them stale
+    pub fn with_proof_cache(mut self) -> Self {
+        self.proof_cache = Some(BTreeMap::new());
+        self
+    }
+
+    /// `proof(index)`, served from the cache while no write reached its path. without
+    /// `with_proof_cache` every call proves afresh
+    pub fn cached_proof(&mut self, index: u32) -> Result<Proof<N>> {
+        if let Some(proof) = self.proof_cache.as_ref().and_then(|cache| cache.get(&index)) {
+            return Ok(proof.clone());
+        }
+
+        let proof = self.proof(index)?;
+        if let Some(cache) = &mut self.proof_cache {
+            cache.insert(index, proof.clone());
+        }
+        Ok(proof)
+    }
+
+    /// whether `cached_proof(index)` would be served from the cache
+    pub fn cache_contains(&self, index: u32) -> bool {
+        self.proof_cache.as_ref().is_some_and(|cache| cache.contains_key(&index))
+    }
+
+    /// drops every cached proof with a sibling at or above leaf `index`, just written. the proof of
+    /// `index` takes the new leaf, or is dropped too if the leaf was removed
+    pub(super) fn invalidate_leaf_proofs(&mut self, index: u32) {
+        let leaf = self.nodes.get((index + self.zero_index) as usize);
+        let depth = self.current_depth();
+        if let Some(cache) = &mut self.proof_cache {
+            cache.retain(|&proven, _| !path_covers(proven, index, depth));
+            match (cache.get_mut(&index), leaf) {
+                (Some(proof), Some(leaf)) => proof.leaf = leaf,
+                (Some(_), None) => {
+                    cache.remove(&index);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub(super) fn invalidate_all_proofs(&mut self) {
+        if let Some(cache) = &mut self.proof_cache {
+            cache.clear();
+        }
+    }
+}
+
+/// whether a sibling on the `depth` long path of leaf `proven` is leaf `leaf` or one of its ancestors
+fn path_covers(proven: u32, leaf: u32, depth: u32) -> bool {
+    (0..depth).any(|level| (proven >> level) ^ 1 == leaf >> level)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::hash::{Hasher, ShaHasher};
+    use crate::Hash;
+
+    use super::*;
+
+    fn cached_tree(size: u32) -> MerkleTree {
+        let leaves: Vec<Hash> = (0..size).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let mut tree = MerkleTree::from_leaves(4, ShaHasher::default(), &leaves).unwrap().with_proof_cache();
+        for i in 0..size {
+            tree.cached_proof(i).unwrap();
+        }
+        tree
+    }
+
+    /// every cached proof still in the cache must equal a fresh one
+    fn assert_cache_fresh(tree: &MerkleTree) {
+        for i in 0..tree.size() {
+            if tree.cache_contains(i) {
+                let cached = tree.proof_cache.as_ref().unwrap()[&i].clone();
+                assert_eq!(cached, tree.proof(i).unwrap());
+                assert!(cached.verify(&tree.root_hash().unwrap(), tree.hasher()));
+            }
+        }
+    }
+
+    #[test]
+    fn update_invalidates_exactly_the_proofs_through_its_ancestors() {
+        for updated in 0..6 {
+            let mut tree = cached_tree(6);
+            let stale: Vec<bool> = (0..6).map(|i| path_covers(i, updated, tree.current_depth())).collect();
+
+            tree.update(updated, [7u8; 32]).unwrap();
+
+            for i in 0..6 {
+                assert_eq!(tree.cache_contains(i), !stale[i as usize], "leaf {} after updating {}", i, updated);
+                assert_eq!(stale[i as usize], i != updated);
+            }
+            assert_cache_fresh(&tree);
+        }
+    }
+
+    #[test]
+    fn every_write_keeps_cache_fresh() {
+        let mut tree = cached_tree(6);
+        tree.update_many(&[(1, [1u8; 32]), (4, [4u8; 32])]).unwrap();
+        // 1 and 4 each cover the other's path
+        assert!((0..6).all(|i| !tree.cache_contains(i)));
+        assert_cache_fresh(&tree);
+
+        let mut tree = cached_tree(6);
+        tree.remove(2).unwrap();
+        assert!(!tree.cache_contains(2));
+        assert_cache_fresh(&tree);
+
+        let mut tree = cached_tree(6);
+        tree.set_leaf(3, [3u8; 32]).unwrap();
+        assert!(tree.cache_contains(3));
+        assert_cache_fresh(&tree);
+
+        for grow in [|tree: &mut MerkleTree| { tree.add([9u8; 32]); },
+                     |tree: &mut MerkleTree| { tree.set_leaf(7, [9u8; 32]).unwrap(); },
+                     |tree: &mut MerkleTree| tree.reserve_for_total(20).unwrap(),
+                     |tree: &mut MerkleTree| tree.clear()] {
+            let mut tree = cached_tree(6);
+            grow(&mut tree);
+            assert!((0..6).all(|i| !tree.cache_contains(i)));
+        }
+
+        let mut tree = cached_tree(6);
+        tree.begin_defer();
+        tree.add([9u8; 32]);
+        tree.cached_proof(0).unwrap();
+        tree.commit();
+        assert!(!tree.cache_contains(0));
+        assert_cache_fresh(&tree);
+    }
+
+    #[test]
+    fn cache_is_off_by_default() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        tree.add([1u8; 32]);
+        assert_eq!(tree.cached_proof(0).unwrap(), tree.proof(0).unwrap());
+        assert!(!tree.cache_contains(0));
+    }
+}
@@ -0,0 +1,208 @@
+use alloc::{sync::Arc, vec::Vec};
+use anyhow::{bail, Result};
+use core::convert::TryInto;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::hash::Hasher;
+use crate::nodes::PackedNodes;
+
+use super::{Hash, MerkleTree, MerkleTreeError, ZeroLeafPolicy};
+
+/// Everything of a `MerkleTree` but its hasher, for serde. see `MerkleTree::from_parts`.
+///
+/// Hashes are kept as raw bytes: `nodes` holds every slot back to back (empty ones zeroed)
+/// and `occupancy` has a bit per slot, as in `PackedNodes::occupancy_bitmap`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTreeState {
+    levels: u32,
+    root: u32,
+    zero_index: u32,
+    current_add_position: u64,
+    default_hash: Vec<u8>,
+    nodes: Vec<u8>,
+    occupancy: Vec<u64>,
+    root_history: Vec<(u32, Vec<u8>)>,
+    root_history_limit: u64,
+    #[serde(default)]
+    metadata: Vec<Option<u64>>,
+    /// `default_hash` came from `new_with_default` rather than the hasher
+    #[serde(default)]
+    custom_default: bool,
+    #[serde(default)]
+    zero_leaf_policy: ZeroLeafPolicy,
+    /// made by `new_sparse`, every slot is counted in the size
+    #[serde(default)]
+    sparse: bool,
+}
+
+impl<const N: usize> MerkleTree<N> {
+    /// copies the tree data out. adds still deferred by `begin_defer` must be committed first,
+    /// their internal nodes aren't calculated yet
+    pub fn to_state(&self) -> MerkleTreeState {
+        MerkleTreeState {
+            levels: self.levels,
+            root: self.root,
+            zero_index: self.zero_index,
+            current_add_position: self.current_add_position as u64,
+            default_hash: self.default_hash.to_vec(),
+            nodes: self.nodes.iter().flat_map(|n| n.copied().unwrap_or([0u8; N])).collect(),
+            occupancy: self.nodes.occupancy_bitmap().to_vec(),
+            root_history: self.root_history.iter().map(|(size, root)| (*size, root.to_vec())).collect(),
+            root_history_limit: self.root_history_limit as u64,
+            metadata: self.metadata.clone(),
+            custom_default: self.default_hash != self.hasher.generate_hash(&[0u8; N]),
+            zero_leaf_policy: self.zero_leaf_policy,
+            sparse: self.sparse,
+        }
+    }
+
+    /// restores a tree from `to_state` (or deserialized) data without recomputing any hash.
+    /// `hasher` must be the one the tree was built with, checked through the default hash, or for a
+    /// `new_with_default` tree on the root and its children (a tree of a single leaf can't be checked)
+    pub fn from_parts(hasher: impl Hasher<N> + 'static, state: MerkleTreeState) -> Result<Self> {
+        let MerkleTreeState { levels, root, zero_index, current_add_position, default_hash, nodes, occupancy, root_history, root_history_limit, metadata, custom_default, zero_leaf_policy, sparse } = state;
+
+        if !(1..=MerkleTree::MAX_LEVELS).contains(&levels) {
+            bail!(MerkleTreeError::StateError)
+        }
+        let nodes_size = (1usize << levels) - 1;
+        let current_add_position = current_add_position as usize;
+        if zero_index as usize != (nodes_size - 1) / 2 || nodes.len() != nodes_size * N || occupancy.len() != nodes_size.div_ceil(64)
+            || current_add_position < zero_index as usize || current_add_position > nodes_size || (sparse && current_add_position != nodes_size) {
+            bail!(MerkleTreeError::StateError)
+        }
+
+        let size = (current_add_position - zero_index as usize) as u32;
+        if root != (1 << (levels - 1 - MerkleTree::depth_for(size))) - 1 || metadata.len() > size as usize {
+            bail!(MerkleTreeError::StateError)
+        }
+
+        let default_hash: Hash<N> = default_hash.as_slice().try_into().map_err(|_| MerkleTreeError::StateError)?;
+        if !custom_default && default_hash != hasher.generate_hash(&[0u8; N]) {
+            bail!(MerkleTreeError::StateError)
+        }
+
+        let root_history = root_history.into_iter()
+            .map(|(size, root)| Ok((size, root.as_slice().try_into().map_err(|_| MerkleTreeError::StateError)?)))
+            .collect::<Result<_>>()?;
+
+        let hashes = nodes.chunks_exact(N).map(|chunk| chunk.try_into().unwrap()).collect();
+        let nodes = PackedNodes::from_raw(hashes, occupancy.into_boxed_slice());
+        let empty_subtrees = Self::empty_subtrees(&hasher, default_hash, levels);
+        if custom_default && root < zero_index {
+            let (left, right) = MerkleTree::child_nodes(root);
+            let empty = empty_subtrees[(levels - 2 - MerkleTree::node_depth(root)) as usize];
+            let child = |node: u32| nodes.get(node as usize).unwrap_or(empty);
+            if !nodes.get(root as usize).is_some_and(|parent| hasher.verify_pair(&child(left), &child(right), &parent)) {
+                bail!(MerkleTreeError::StateError)
+            }
+        }
+
+        Ok(MerkleTree {
+            hasher: Arc::new(hasher),
+            levels,
+            root,
+            zero_index,
+            current_add_position,
+            current_root: nodes.get(root as usize),
+            nodes,
+            default_hash,
+            empty_subtrees,
+            deferred_from: None,
+            deferring: false,
+            root_history,
+            root_history_limit: root_history_limit as usize,
+            observer: None,
+            zero_leaf_policy,
+            sparse,
+            metadata,
+            proof_cache: None,
+        })
+    }
+}
+
+/// serializes `to_state`. fails for a tree with uncommitted deferred adds
+impl<const N: usize> Serialize for MerkleTree<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+        if self.deferred_from.is_some() {
+            return Err(serde::ser::Error::custom("tree has uncommitted deferred adds"));
+        }
+        self.to_state().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::{Sha3_512Hasher, ShaHasher};
+    use crate::hash::Truncating;
+
+    use super::*;
+
+    #[test]
+    fn serde_round_trip_keeps_root() {
+        let mut tree = MerkleTree::new(10, ShaHasher::default()).with_root_history(4);
+        for i in 0..tree.capacity() - 1 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+        tree.add_with_metadata([1u8; 32], 42).unwrap();
+
+        let json = serde_json::to_string(&tree).unwrap();
+        let state: MerkleTreeState = serde_json::from_str(&json).unwrap();
+        let mut restored = MerkleTree::from_parts(ShaHasher::default(), state).unwrap();
+
+        assert_eq!(restored.root_hash(), tree.root_hash());
+        assert!(restored.leaves().eq(tree.leaves()));
+        assert_eq!(restored.leaf_metadata(tree.size() - 1), Some(42));
+        assert_eq!(restored.historical_root(tree.size()), tree.root_hash());
+        assert!(restored.verify_leaves(&tree.leaves().copied().collect::<Vec<_>>()).unwrap());
+
+        let leaf = restored.generate_hash("hello".as_bytes());
+        restored.update(3, leaf).unwrap();
+        tree.update(3, leaf).unwrap();
+        assert_eq!(restored.root_hash(), tree.root_hash());
+
+        let mut tree = MerkleTree::new_sparse(3, ShaHasher::default()).with_zero_leaf_policy(ZeroLeafPolicy::Occupancy);
+        tree.set_leaf(1, tree.empty_root()).unwrap();
+        let json = serde_json::to_string(&tree).unwrap();
+        let mut restored = MerkleTree::from_parts(ShaHasher::default(), serde_json::from_str(&json).unwrap()).unwrap();
+        assert!(restored.proof(1).is_ok());
+        assert!(restored.proof(0).is_err());
+        restored.clear();
+        assert_eq!(restored.size(), restored.capacity());
+    }
+
+    #[test]
+    fn from_parts_rejects_other_hasher_and_bad_state() {
+        let mut tree = MerkleTree::new(3, ShaHasher::default());
+        tree.add(tree.generate_hash("hello".as_bytes()));
+
+        assert!(MerkleTree::from_parts(Truncating::new(Sha3_512Hasher::default()), tree.to_state()).is_err());
+
+        let mut state = tree.to_state();
+        state.nodes.pop();
+        assert!(MerkleTree::from_parts(ShaHasher::default(), state).is_err());
+
+        let mut state = tree.to_state();
+        state.root = 0;
+        assert!(MerkleTree::from_parts(ShaHasher::default(), state).is_err());
+
+        tree.begin_defer();
+        tree.add(tree.generate_hash("12345".as_bytes()));
+        assert!(serde_json::to_string(&tree).is_err());
+    }
+
+    #[test]
+    fn from_parts_keeps_custom_default() {
+        let mut tree = MerkleTree::new_with_default(3, ShaHasher::default(), [0u8; 32]);
+        for i in 0..3u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        let restored = MerkleTree::from_parts(ShaHasher::default(), tree.to_state()).unwrap();
+        assert_eq!(restored.empty_root(), [0u8; 32]);
+        assert_eq!(restored.root_hash(), tree.root_hash());
+        assert!(restored.audit().is_sound());
+
+        assert!(MerkleTree::from_parts(Truncating::new(Sha3_512Hasher::default()), tree.to_state()).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+use alloc::vec::Vec;
+use anyhow::{bail, Result};
+use core::convert::TryInto;
+
+use crate::merkletree::MerkleTreeError;
+use crate::{Hash, MerkleTree};
+
+/// Follower side of tree replication: applies deltas of a leader tree's updates.
+///
+/// Both trees must start with the same leaves (e.g. built with `from_leaves_with_levels`).
+/// The leader encodes every `update_with_changes` result with `encode_delta` and ships it;
+/// the follower only commits a delta when its own recomputed path matches it.
+///
+/// Delta layout (big endian): `count: u32 | (node: u32 | hash: [u8; 32]) * count`,
+/// nodes ordered from the leaf up to the floating root.
+pub struct TreeMirror {
+    tree: MerkleTree,
+}
+
+impl TreeMirror {
+    const ENTRY_SIZE: usize = 4 + MerkleTree::HASH_SIZE_BYTES;
+
+    pub fn new(tree: MerkleTree) -> Self {
+        TreeMirror { tree }
+    }
+
+    pub fn encode_delta(changes: &[(u32, Hash)]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + changes.len() * Self::ENTRY_SIZE);
+        bytes.extend_from_slice(&(changes.len() as u32).to_be_bytes());
+        for (node, hash) in changes {
+            bytes.extend_from_slice(&node.to_be_bytes());
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    pub fn decode_delta(delta: &[u8]) -> Result<Vec<(u32, Hash)>> {
+        if delta.len() < 4 {
+            bail!(MerkleTreeError::DeltaError)
+        }
+
+        let count = u32::from_be_bytes(delta[..4].try_into()?) as usize;
+        let body = &delta[4..];
+        if body.len() != count * Self::ENTRY_SIZE {
+            bail!(MerkleTreeError::DeltaError)
+        }
+
+        Ok(body.chunks_exact(Self::ENTRY_SIZE)
+            .map(|entry| (u32::from_be_bytes(entry[..4].try_into().unwrap()), entry[4..].try_into().unwrap()))
+            .collect())
+    }
+
+    /// validates `delta` against the follower tree and applies it. a rejected delta leaves the tree untouched
+    pub fn apply_delta(&mut self, delta: &[u8]) -> Result<()> {
+        let changes = Self::decode_delta(delta)?;
+        self.tree.apply_changes(&changes)
+    }
+
+    pub fn tree(&self) -> &MerkleTree {
+        &self.tree
+    }
+
+    pub fn into_inner(self) -> MerkleTree {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::{Hasher, ShaHasher};
+
+    use super::*;
+
+    #[test]
+    fn follower_converges_to_leader_root() {
+        let hasher = ShaHasher::default();
+        let leaves: Vec<Hash> = (0..6u32).map(|i| hasher.generate_hash(&i.to_be_bytes())).collect();
+
+        let mut leader = MerkleTree::from_leaves_with_levels(&leaves, 4, ShaHasher::default()).unwrap();
+        let mut mirror = TreeMirror::new(MerkleTree::from_leaves_with_levels(&leaves, 4, ShaHasher::default()).unwrap());
+
+        for &(index, data) in &[(1u32, "hello"), (5, "12345"), (1, "world")] {
+            let changes = leader.update_with_changes(index, hasher.generate_hash(data.as_bytes())).unwrap();
+            let delta = TreeMirror::encode_delta(&changes);
+            mirror.apply_delta(&delta).unwrap();
+            assert_eq!(mirror.tree().hash_of(0), leader.hash_of(0));
+        }
+
+        let mut changes = leader.update_with_changes(2, hasher.generate_hash("bad".as_bytes())).unwrap();
+        let root_before = mirror.tree().hash_of(0);
+        changes.last_mut().unwrap().1 = [0u8; 32];
+        assert!(mirror.apply_delta(&TreeMirror::encode_delta(&changes)).is_err());
+        assert!(mirror.apply_delta(&TreeMirror::encode_delta(&changes[..2])).is_err());
+        assert!(mirror.apply_delta(&[0, 0, 0, 1]).is_err());
+        assert_eq!(mirror.tree().hash_of(0), root_before);
+    }
+}
@@ -0,0 +1,164 @@
+use alloc::{boxed::Box, vec};
+
+use crate::{Hash, OptionHash};
+
+/// Node storage keeping hashes and their occupancy apart.
+///
+/// `Option<[u8; 32]>` has no niche, so a boxed slice of them spends a whole extra byte
+/// (plus padding) per node on the tag. Here occupancy is a bit per node instead.
+#[derive(Clone)]
+pub struct PackedNodes<const N: usize = 32> {
+    hashes: Box<[Hash<N>]>,
+    occupancy: Box<[u64]>,
+}
+
+impl<const N: usize> PackedNodes<N> {
+    pub fn new(len: usize) -> Self {
+        PackedNodes {
+            hashes: vec![[0u8; N]; len].into_boxed_slice(),
+            occupancy: vec![0u64; len.div_ceil(64)].into_boxed_slice(),
+        }
+    }
+
+    /// `occupancy` must have a bit per hash, as `occupancy_bitmap` returns
+    #[cfg(feature = "serde")]
+    pub(crate) fn from_raw(hashes: Box<[Hash<N>]>, occupancy: Box<[u64]>) -> Self {
+        debug_assert_eq!(occupancy.len(), hashes.len().div_ceil(64));
+        PackedNodes { hashes, occupancy }
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// number of set nodes
+    pub fn occupied(&self) -> usize {
+        self.occupancy.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// bytes of the occupancy bitmap
+    pub fn bitmap_bytes(&self) -> usize {
+        self.occupancy.len() * core::mem::size_of::<u64>()
+    }
+
+    pub fn is_set(&self, index: usize) -> bool {
+        self.occupancy[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn get(&self, index: usize) -> OptionHash<N> {
+        self.get_ref(index).copied()
+    }
+
+    pub fn get_ref(&self, index: usize) -> Option<&Hash<N>> {
+        if self.is_set(index) {
+            Some(&self.hashes[index])
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, index: usize, value: Hash<N>) {
+        self.hashes[index] = value;
+        self.occupancy[index / 64] |= 1 << (index % 64);
+    }
+
+    /// marks `index` empty and zeroes its bytes. with the `zeroize` feature the write
+    /// can't be optimized away, which costs a little but leaves no residue of the old hash
+    pub fn clear(&mut self, index: usize) {
+        #[cfg(feature = "zeroize")]
+        zeroize::Zeroize::zeroize(&mut self.hashes[index]);
+        #[cfg(not(feature = "zeroize"))]
+        {
+            self.hashes[index] = [0u8; N];
+        }
+        self.occupancy[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// `clear` of every node, keeping the allocation
+    pub fn clear_all(&mut self) {
+        #[cfg(feature = "zeroize")]
+        for hash in self.hashes.iter_mut() {
+            zeroize::Zeroize::zeroize(hash);
+        }
+        #[cfg(not(feature = "zeroize"))]
+        self.hashes.fill([0u8; N]);
+        self.occupancy.fill(0);
+    }
+
+    /// bit `i % 64` of word `i / 64` tells whether node `i` is set
+    pub fn occupancy_bitmap(&self) -> &[u64] {
+        &self.occupancy
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=Option<&Hash<N>>> + '_ {
+        (0..self.len()).map(move |i| self.get_ref(i))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn as_ptr(&self) -> *const Hash<N> {
+        self.hashes.as_ptr()
+    }
+}
+
+/// scrubs every node when the storage is reset (rebuilt) or dropped
+#[cfg(feature = "zeroize")]
+impl<const N: usize> Drop for PackedNodes<N> {
+    fn drop(&mut self) {
+        for hash in self.hashes.iter_mut() {
+            zeroize::Zeroize::zeroize(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_clear_round_trip() {
+        let mut nodes = PackedNodes::new(130);
+        assert_eq!(nodes.occupancy_bitmap().len(), 3);
+        assert!(nodes.iter().all(|n| n.is_none()));
+
+        for &i in &[0usize, 63, 64, 127, 128, 129] {
+            nodes.set(i, [i as u8; 32]);
+            assert_eq!(nodes.get(i), Some([i as u8; 32]));
+        }
+        assert_eq!(nodes.occupancy_bitmap(), &[1 | 1 << 63, 1 | 1 << 63, 0b11]);
+        assert_eq!(nodes.get(1), None);
+        assert_eq!(nodes.get(62), None);
+
+        nodes.clear(63);
+        nodes.clear(128);
+        assert_eq!(nodes.get(63), None);
+        assert_eq!(nodes.get(128), None);
+        assert_eq!(nodes.get(64), Some([64u8; 32]));
+        assert_eq!(nodes.occupancy_bitmap(), &[1, 1 | 1 << 63, 0b10]);
+        assert_eq!(nodes.occupied(), 4);
+
+        nodes.clear_all();
+        assert_eq!(nodes.occupied(), 0);
+        assert_eq!(nodes.len(), 130);
+    }
+
+    #[test]
+    fn clear_zeroes_bytes() {
+        let mut nodes = PackedNodes::new(2);
+        nodes.set(1, [0xab; 32]);
+        nodes.clear(1);
+        assert_eq!(nodes.hashes[1], [0u8; 32]);
+    }
+
+    #[test]
+    fn set_overwrites_value() {
+        let mut nodes = PackedNodes::new(1);
+        nodes.set(0, [1u8; 32]);
+        nodes.set(0, [2u8; 32]);
+        assert_eq!(nodes.get_ref(0), Some(&[2u8; 32]));
+        assert_eq!(nodes.iter().count(), 1);
+    }
+}
@@ -0,0 +1,79 @@
+/// Callbacks for operation metrics, see `MerkleTree::with_observer`.
+///
+/// Every method defaults to a no-op, so an observer overrides only what it counts.
+/// They run inline with the operation and take `&self`, so counters need interior
+/// mutability (atomics). Without an observer the tree only pays an `Option` check.
+/// `Send + Sync` like `Hasher`, so observing doesn't stop a tree from being shared across threads
+pub trait TreeObserver: Send + Sync {
+    /// leaf `index` was appended
+    fn on_add(&self, _index: u32) {}
+
+    /// leaf `index` was replaced
+    fn on_update(&self, _index: u32) {}
+
+    /// a proof of leaf `index` was built
+    fn on_proof(&self, _index: u32) {}
+
+    /// the tree hashed a pair of children. leaf hashing by callers isn't counted
+    fn on_hash_call(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::hash::{Hasher, ShaHasher};
+    use crate::MerkleTree;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counts {
+        adds: AtomicUsize,
+        updates: AtomicUsize,
+        proofs: AtomicUsize,
+        hashes: AtomicUsize,
+    }
+
+    struct Counting(Arc<Counts>);
+
+    impl TreeObserver for Counting {
+        fn on_add(&self, _index: u32) {
+            self.0.adds.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_update(&self, _index: u32) {
+            self.0.updates.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_proof(&self, _index: u32) {
+            self.0.proofs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn on_hash_call(&self) {
+            self.0.hashes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn observer_counts_operations() {
+        let counts = Arc::new(Counts::default());
+        let mut tree = MerkleTree::new(3, ShaHasher::default()).with_observer(Box::new(Counting(counts.clone())));
+
+        // root climbs as the tree fills: 0, 1, 2 and 2 pair hashes
+        for i in 0..4u32 {
+            tree.add(ShaHasher::default().generate_hash(&i.to_be_bytes()));
+        }
+        tree.update(0, [1u8; 32]).unwrap();
+        tree.update_many(&[(1, [2u8; 32]), (3, [3u8; 32])]).unwrap();
+        tree.proof(1).unwrap();
+        tree.proof(2).unwrap();
+        assert!(tree.update(9, [0u8; 32]).is_err());
+
+        assert_eq!(counts.adds.load(Ordering::Relaxed), 4);
+        assert_eq!(counts.updates.load(Ordering::Relaxed), 3);
+        assert_eq!(counts.proofs.load(Ordering::Relaxed), 2);
+        assert_eq!(counts.hashes.load(Ordering::Relaxed), 5 + 2 + 3);
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::{sync::Arc, vec::Vec};
+use anyhow::{bail, Result};
+
+use crate::hash::Hasher;
+use crate::merkletree::MerkleTreeError;
+use crate::{Hash, OptionHash};
+
+enum Node {
+    Empty,
+    Leaf(Hash),
+    Branch(Hash, Arc<Node>, Arc<Node>),
+}
+
+impl Node {
+    fn hash(&self) -> OptionHash {
+        match self {
+            Node::Empty => None,
+            Node::Leaf(hash) | Node::Branch(hash, _, _) => Some(*hash),
+        }
+    }
+}
+
+/// Immutable version of the floating root subtree of a `MerkleTree`, see `MerkleTree::snapshot`.
+///
+/// Nodes are shared between versions, so `update` allocates only the `O(log n)` nodes
+/// on the updated path and leaves every older version intact.
+#[derive(Clone)]
+pub struct PersistentMerkleTree {
+    hasher: Arc<dyn Hasher>,
+    /// `MerkleTree::empty_subtree_hashes`, what absent nodes fold as per height
+    empty_subtrees: Vec<Hash>,
+    root: Arc<Node>,
+    depth: u32,
+    size: u32,
+}
+
+impl PersistentMerkleTree {
+    /// `leaves` are the leaf slots under the floating root, `2^depth` of them
+    pub(crate) fn build(hasher: Arc<dyn Hasher>, empty_subtrees: Vec<Hash>, depth: u32, size: u32, leaves: &[OptionHash]) -> Self {
+        let root = Self::build_node(hasher.as_ref(), &empty_subtrees, leaves);
+        PersistentMerkleTree { hasher, empty_subtrees, root, depth, size }
+    }
+
+    fn build_node(hasher: &dyn Hasher, empty_subtrees: &[Hash], leaves: &[OptionHash]) -> Arc<Node> {
+        if leaves.len() == 1 {
+            return Arc::new(leaves[0].map_or(Node::Empty, Node::Leaf));
+        }
+
+        let (left, right) = leaves.split_at(leaves.len() / 2);
+        let empty = &empty_subtrees[left.len().trailing_zeros() as usize];
+        let left = Self::build_node(hasher, empty_subtrees, left);
+        let right = Self::build_node(hasher, empty_subtrees, right);
+        Self::branch(hasher, empty, left, right)
+    }
+
+    /// parent of two children whose absent side folds as `empty`, the empty subtree of their height
+    fn branch(hasher: &dyn Hasher, empty: &Hash, left: Arc<Node>, right: Arc<Node>) -> Arc<Node> {
+        match (left.hash(), right.hash()) {
+            (None, None) => Arc::new(Node::Empty),
+            (l, r) => {
+                let hash = hasher.concat_hash(&l.unwrap_or(*empty), &r.unwrap_or(*empty));
+                Arc::new(Node::Branch(hash, left, right))
+            }
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn root_hash(&self) -> OptionHash {
+        self.root.hash()
+    }
+
+    pub fn leaf(&self, index: u32) -> OptionHash {
+        if index >= self.size {
+            return None;
+        }
+
+        let mut node = &self.root;
+        for level in (0..self.depth).rev() {
+            node = match node.as_ref() {
+                Node::Branch(_, left, right) => if index >> level & 1 == 0 { left } else { right },
+                _ => return None,
+            };
+        }
+
+        node.hash()
+    }
+
+    /// new version with leaf `index` replaced. `self` is left untouched
+    pub fn update(&self, index: u32, value: Hash) -> Result<PersistentMerkleTree> {
+        if index >= self.size {
+            bail!(MerkleTreeError::UpdateIndexError)
+        }
+
+        let root = self.update_node(&self.root, self.depth, index, value);
+        Ok(PersistentMerkleTree { root, ..self.clone() })
+    }
+
+    fn update_node(&self, node: &Arc<Node>, level: u32, index: u32, value: Hash) -> Arc<Node> {
+        if level == 0 {
+            return Arc::new(Node::Leaf(value));
+        }
+
+        let (left, right) = match node.as_ref() {
+            Node::Branch(_, left, right) => (left.clone(), right.clone()),
+            _ => (Arc::new(Node::Empty), Arc::new(Node::Empty)),
+        };
+
+        let hasher = self.hasher.as_ref();
+        let empty = &self.empty_subtrees[level as usize - 1];
+        if index >> (level - 1) & 1 == 0 {
+            let left = self.update_node(&left, level - 1, index, value);
+            Self::branch(hasher, empty, left, right)
+        } else {
+            let right = self.update_node(&right, level - 1, index, value);
+            Self::branch(hasher, empty, left, right)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::ShaHasher;
+    use crate::MerkleTree;
+
+    #[test]
+    fn old_snapshots_keep_their_roots() {
+        let mut tree = MerkleTree::new(4, ShaHasher::default());
+        for i in 0..6u32 {
+            tree.add(tree.generate_hash(&i.to_be_bytes()));
+        }
+
+        let first = tree.snapshot();
+        let first_root = first.root_hash();
+        assert_eq!(first_root, tree.hash_of(0));
+
+        let new_leaf = tree.generate_hash("12345".as_bytes());
+        let second = first.update(4, new_leaf).unwrap();
+        let third = second.update(0, new_leaf).unwrap();
+
+        tree.update(4, new_leaf).unwrap();
+        assert_eq!(second.root_hash(), tree.hash_of(0));
+        tree.update(0, new_leaf).unwrap();
+        assert_eq!(third.root_hash(), tree.hash_of(0));
+
+        assert_eq!(first.root_hash(), first_root);
+        assert_eq!(first.leaf(4), Some(tree.generate_hash(&4u32.to_be_bytes())));
+        assert_eq!(third.leaf(4), Some(new_leaf));
+        assert_eq!(third.leaf(6), None);
+        assert!(third.update(6, new_leaf).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use alloc::{vec, vec::Vec};
+use anyhow::{bail, Result};
+use core::convert::TryInto;
+
+use crate::hash::Hasher;
+use crate::merkletree::{verify_consistency, verify_indexed_leaf};
+use crate::{Hash, MerkleTreeError};
+
+const PROOF_MAGIC: &[u8; 4] = b"MTPF";
+const PROOF_VERSION: u8 = 1;
+const PROOF_HEADER_SIZE: usize = 4 + 1 + 1 + 1 + 4 + 4;
+
+/// Self-contained inclusion proof of one leaf, see `MerkleTree::proof`.
+///
+/// `siblings` are the leaf's `subtree_proof`, lowest first up to the floating root of a tree
+/// of `size` leaves. Bit `i` of `index` tells the side of `siblings[i]`: set means it's the left child.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof<const N: usize = 32> {
+    pub leaf: Hash<N>,
+    pub index: u32,
+    pub siblings: Vec<Hash<N>>,
+    pub size: u32,
+}
+
+impl<const N: usize> Proof<N> {
+    pub fn verify(&self, root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+        verify_indexed_leaf(root, &self.leaf, self.index, &self.siblings, self.size, hasher)
+    }
+
+    /// root `leaf` folds to along `siblings`. unlike `verify` the length isn't checked against `size`
+    pub fn recompute_root(&self, hasher: &dyn Hasher<N>) -> Hash<N> {
+        self.path().iter().fold(self.leaf, |hash, (sibling, is_right)| {
+            if *is_right { hasher.concat_hash(&hash, sibling) } else { hasher.concat_hash(sibling, &hash) }
+        })
+    }
+
+    /// siblings each with `true` when it's the right child, the form `merkletree::verify_proof` takes
+    pub fn path(&self) -> Vec<(Hash<N>, bool)> {
+        self.siblings.iter()
+            .enumerate()
+            .map(|(level, sibling)| (*sibling, level >= 32 || self.index >> level & 1 == 0))
+            .collect()
+    }
+
+    /// language neutral encoding, see `from_bytes`.
+    ///
+    /// Layout (big endian): `magic: "MTPF" | version: u8 | hash_len: u8 | count: u8 | index: u32 |
+    /// size: u32 | sides: [u8; ceil(count / 8)] | leaf: [u8; hash_len] | siblings: [u8; hash_len] * count`.
+    /// bit `i % 8` (least significant first) of `sides[i / 8]` is set when `siblings[i]` is the right
+    /// child, as in `path`. unused bits are zero. errors with `ProofFormatError` for more than 255
+    /// siblings or hashes longer than 255 bytes, which the header can't hold
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let count = self.siblings.len();
+        if count > u8::MAX as usize || N > u8::MAX as usize {
+            bail!(MerkleTreeError::ProofFormatError)
+        }
+
+        let mut sides = vec![0u8; count.div_ceil(8)];
+        for (i, (_, is_right)) in self.path().iter().enumerate() {
+            if *is_right {
+                sides[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(PROOF_HEADER_SIZE + sides.len() + (1 + count) * N);
+        bytes.extend_from_slice(PROOF_MAGIC);
+        bytes.extend_from_slice(&[PROOF_VERSION, N as u8, count as u8]);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&sides);
+        bytes.extend_from_slice(&self.leaf);
+        for sibling in &self.siblings {
+            bytes.extend_from_slice(sibling);
+        }
+        Ok(bytes)
+    }
+
+    /// decodes `to_bytes` output. errors with `ProofFormatError` on a bad header or length, an
+    /// index outside `size`, or side bits disagreeing with the index
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < PROOF_HEADER_SIZE || &bytes[..4] != PROOF_MAGIC || bytes[4] != PROOF_VERSION || bytes[5] as usize != N {
+            bail!(MerkleTreeError::ProofFormatError)
+        }
+
+        let count = bytes[6] as usize;
+        let index = u32::from_be_bytes(bytes[7..11].try_into()?);
+        let size = u32::from_be_bytes(bytes[11..15].try_into()?);
+        let sides_len = count.div_ceil(8);
+        let body = &bytes[PROOF_HEADER_SIZE..];
+        if index >= size || body.len() != sides_len + (1 + count) * N {
+            bail!(MerkleTreeError::ProofFormatError)
+        }
+
+        let (sides, hashes) = body.split_at(sides_len);
+        let mut chunks = hashes.chunks_exact(N).map(|chunk| chunk.try_into().unwrap());
+        let leaf = chunks.next().unwrap();
+        let proof = Proof { leaf, index, siblings: chunks.collect(), size };
+
+        if proof.to_bytes()?[PROOF_HEADER_SIZE..PROOF_HEADER_SIZE + sides_len] != *sides {
+            bail!(MerkleTreeError::ProofFormatError)
+        }
+        Ok(proof)
+    }
+}
+
+/// Inclusion proof of several leaves at once, see `MerkleTree::multiproof` and `verify_multiproof`.
+///
+/// `hashes` are only the siblings the leaves can't rebuild themselves: level by level from the
+/// leaves up, ascending within a level, skipping every node that is itself on a proven path.
+/// so leaves sharing ancestors share their upper siblings too
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MultiProof<const N: usize = 32> {
+    pub hashes: Vec<Hash<N>>,
+    pub size: u32,
+}
+
+/// `Proof` carrying per level whether the sibling was a stored node or an absent (empty) subtree,
+/// see `MerkleTree::detailed_proof` and `merkletree::verify_proof_detailed`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DetailedProof<const N: usize = 32> {
+    pub proof: Proof<N>,
+    /// `present[i]` is false when `proof.siblings[i]` is the empty subtree hash of level `i`
+    pub present: Vec<bool>,
+}
+
+/// Membership proof of one leaf together with the consistency proof from an older size of the
+/// same tree to the one the leaf is proven in, see `MerkleTree::combined_proof`.
+///
+/// a light client checks both in one round trip: the leaf is in the tree and that tree only
+/// appended to the one of `old_size` leaves it already trusted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CombinedProof<const N: usize = 32> {
+    pub membership: Proof<N>,
+    pub old_size: u32,
+    /// `MerkleTree::consistency_proof(old_size)`, from `old_size` to `membership.size`
+    pub consistency: Vec<Hash<N>>,
+}
+
+impl<const N: usize> CombinedProof<N> {
+    /// both sub-proofs: the leaf against `new_root`, and `old_root` growing into `new_root`
+    pub fn verify(&self, old_root: &Hash<N>, new_root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+        self.membership.verify(new_root, hasher)
+            && verify_consistency(old_root, self.old_size, new_root, self.membership.size, &self.consistency, hasher)
+    }
+}
+
+/// checks a leaf of an inner tree is committed to by `outer_root` through the outer tree.
+///
+/// Convention: the inner tree's root is the outer leaf as is, without re-hashing, so
+/// `outer.leaf` must equal the root `inner` folds to. `inner` is verified against it
+pub fn chain_verify<const N: usize>(inner: &Proof<N>, outer: &Proof<N>, outer_root: &Hash<N>, hasher: &dyn Hasher<N>) -> bool {
+    inner.verify(&outer.leaf, hasher) && outer.verify(outer_root, hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::ShaHasher;
+    use crate::MerkleTree;
+
+    use super::*;
+
+    #[test]
+    fn chained_membership_of_inner_leaf() {
+        let inner_trees: Vec<MerkleTree> = (0..3u32)
+            .map(|t| (0..5u32).map(|i| ShaHasher::default().generate_hash(&(t * 100 + i).to_be_bytes())).collect())
+            .collect();
+
+        let mut outer = MerkleTree::new(3, ShaHasher::default());
+        for inner in &inner_trees {
+            outer.add(inner.root_hash().unwrap());
+        }
+        let outer_root = outer.root_hash().unwrap();
+
+        let inner_proof = inner_trees[1].proof(4).unwrap();
+        let outer_proof = outer.proof(1).unwrap();
+        assert!(chain_verify(&inner_proof, &outer_proof, &outer_root, outer.hasher()));
+
+        // inner leaf proven against the wrong outer leaf
+        let wrong_outer = outer.proof(2).unwrap();
+        assert!(!chain_verify(&inner_proof, &wrong_outer, &outer_root, outer.hasher()));
+
+        assert_eq!(inner_proof.recompute_root(outer.hasher()), outer_proof.leaf);
+        assert_eq!(outer_proof.recompute_root(outer.hasher()), outer_root);
+
+        let mut forged = inner_proof.clone();
+        forged.leaf = [0u8; 32];
+        assert!(!chain_verify(&forged, &outer_proof, &outer_root, outer.hasher()));
+    }
+
+    #[test]
+    fn proof_bytes_round_trip_and_reject_malformed() {
+        let tree: MerkleTree = (0..11u32).map(|i| ShaHasher::default().generate_hash(&i.to_be_bytes())).collect();
+        let root = tree.root_hash().unwrap();
+        let proof = tree.proof(9).unwrap();
+
+        let bytes = proof.to_bytes().unwrap();
+        assert_eq!(bytes.len(), PROOF_HEADER_SIZE + 1 + 5 * 32);
+        // index 9 is the right child at levels 0 and 3, so its siblings there are left ones
+        assert_eq!(bytes[PROOF_HEADER_SIZE], 0b0110);
+
+        let decoded = Proof::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(&root, tree.hasher()));
+
+        let mut flipped = bytes.clone();
+        flipped[PROOF_HEADER_SIZE] ^= 1;
+        let mut outside = bytes.clone();
+        outside[11..15].copy_from_slice(&9u32.to_be_bytes());
+        let mut wide = bytes.clone();
+        wide[5] = 64;
+        for bad in [&bytes[..bytes.len() - 1], &bytes[..3], &[bytes.clone(), vec![0]].concat(), &flipped, &outside, &wide] {
+            let err = Proof::<32>::from_bytes(bad).unwrap_err();
+            assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::ProofFormatError)));
+        }
+
+        // the count byte can't hold 256 siblings, truncating it would decode as a different proof
+        let long = Proof { siblings: vec![[0u8; 32]; 256], ..proof };
+        let err = long.to_bytes().unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::ProofFormatError)));
+        assert_eq!(Proof { siblings: vec![[0u8; 32]; 255], ..long }.to_bytes().unwrap().len(), PROOF_HEADER_SIZE + 32 + 256 * 32);
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::{bail, Result};
+
+use crate::hash::{Hasher, ShaHasher};
+use crate::{Hash, MerkleTree, MerkleTreeError, OptionHash};
+
+const MAX_LEVELS: usize = 8;
+
+/// `MerkleTree` variant for tiny fixed trees keeping its nodes inline instead of on the heap.
+///
+/// Stable Rust can't size an array by `(1 << LEVELS) - 1`, so the node count is a second
+/// parameter, `NODES`, which must be exactly that: `StackMerkleTree<4, 15>`. Anything else, or
+/// `LEVELS` above `MAX_LEVELS` (8, i.e. 128 leaves and about 8.5KB of nodes), fails to compile.
+/// Layout, floating root and hashing are the same as in `MerkleTree`.
+pub struct StackMerkleTree<const LEVELS: usize, const NODES: usize, H: Hasher = ShaHasher> {
+    hasher: H,
+    nodes: [OptionHash; NODES],
+    /// what an absent node folds as per height, as `MerkleTree::empty_subtree_hashes`
+    empty_subtrees: [Hash; LEVELS],
+    root: usize,
+    size: usize,
+}
+
+impl<const LEVELS: usize, const NODES: usize, H: Hasher> StackMerkleTree<LEVELS, NODES, H> {
+    pub const MAX_LEVELS: usize = MAX_LEVELS;
+    const ZERO_INDEX: usize = (NODES - 1) / 2;
+    const LEVELS_CHECK: () = assert!(LEVELS >= 1 && LEVELS <= Self::MAX_LEVELS, "StackMerkleTree LEVELS must be in [1-8]");
+    const NODES_CHECK: () = assert!(LEVELS > Self::MAX_LEVELS || NODES == (1 << LEVELS) - 1, "StackMerkleTree NODES must be (1 << LEVELS) - 1");
+
+    pub fn new(hasher: H) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = (Self::LEVELS_CHECK, Self::NODES_CHECK);
+
+        let mut empty_subtrees = [hasher.generate_hash(&[0u8; MerkleTree::HASH_SIZE_BYTES]); LEVELS];
+        for h in 1..LEVELS {
+            empty_subtrees[h] = hasher.concat_hash(&empty_subtrees[h - 1], &empty_subtrees[h - 1]);
+        }
+        StackMerkleTree {
+            hasher,
+            nodes: [None; NODES],
+            empty_subtrees,
+            root: Self::ZERO_INDEX,
+            size: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        MerkleTree::capacity_for(LEVELS as u32)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size as u32
+    }
+
+    pub fn root_hash(&self) -> OptionHash {
+        self.nodes[self.root]
+    }
+
+    pub fn leaf(&self, index: u32) -> OptionHash {
+        if index >= self.size() {
+            return None;
+        }
+        self.nodes[Self::ZERO_INDEX + index as usize]
+    }
+
+    /// returns MT index of added value, panics when full
+    pub fn add(&mut self, value: Hash) -> u32 {
+        match self.try_add(value) {
+            Ok(index) => index,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// `add` returning `TreeFull` instead of panicking
+    pub fn try_add(&mut self, value: Hash) -> Result<u32> {
+        if self.capacity() <= self.size() {
+            bail!(MerkleTreeError::TreeFull)
+        }
+
+        let mut node = Self::ZERO_INDEX + self.size;
+        self.nodes[node] = Some(value);
+        self.size += 1;
+
+        let current_depth = match self.size {
+            0 | 1 => 0,
+            size => usize::BITS - (size - 1).leading_zeros(),
+        } as usize;
+        self.root = (1 << (LEVELS - 1 - current_depth)) - 1;
+
+        let mut height = 0;
+        while node != self.root {
+            let parent = (node - 1) / 2;
+            let empty = self.empty_subtrees[height];
+            let left = self.nodes[2 * parent + 1].unwrap_or(empty);
+            let right = self.nodes[2 * parent + 2].unwrap_or(empty);
+            self.nodes[parent] = Some(self.hasher.concat_hash(&left, &right));
+            node = parent;
+            height += 1;
+        }
+
+        Ok((self.size - 1) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stack_tree_matches_heap_tree() {
+        let mut heap = MerkleTree::new(4, ShaHasher::default());
+        let mut stack: StackMerkleTree<4, 15> = StackMerkleTree::new(ShaHasher::default());
+        assert_eq!(stack.capacity(), heap.capacity());
+
+        for i in 0..8u32 {
+            let leaf = heap.generate_hash(&i.to_be_bytes());
+            let appended = heap.add_commit(leaf);
+            assert_eq!(stack.add(leaf), appended.index);
+            assert_eq!(stack.root_hash(), Some(appended.root));
+            assert_eq!(stack.leaf(i), Some(leaf));
+        }
+        assert_eq!(stack.root_hash(), heap.hash_of(0));
+        assert_eq!(stack.leaf(8), None);
+    }
+
+    #[test]
+    fn try_add_stops_at_capacity() {
+        let mut stack: StackMerkleTree<2, 3> = StackMerkleTree::new(ShaHasher::default());
+        assert_eq!(stack.try_add([1u8; 32]).unwrap(), 0);
+        assert_eq!(stack.try_add([2u8; 32]).unwrap(), 1);
+        let root = stack.root_hash();
+
+        let err = stack.try_add([3u8; 32]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<MerkleTreeError>(), Some(MerkleTreeError::TreeFull)));
+        assert_eq!(stack.root_hash(), root);
+    }
+
+    #[test]
+    fn nodes_are_sized_by_levels() {
+        let node = core::mem::size_of::<OptionHash>();
+        assert!(core::mem::size_of::<StackMerkleTree<2, 3>>() < 3 * node + 2 * 32 + 64);
+        assert!(core::mem::size_of::<StackMerkleTree<8, 255>>() >= 255 * node);
+    }
+}